@@ -0,0 +1,80 @@
+/**
+ * Throughput of MessageReader and MessageCodec on a stream of small command frames,
+ * delivered in socket-sized reads so many frames arrive in each one.
+ * Run with: cargo bench --bench message_reader
+ */
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use lib_setup::codec::MessageCodec;
+use lib_setup::message::{Command, Message, Request};
+use lib_setup::message_reader::MessageReader;
+use tokio_util::codec::Decoder;
+
+const COMMANDS: u32 = 50_000;
+const READ_SIZE: usize = 64 * 1024;
+const ROUNDS: u32 = 20;
+
+fn main() {
+    let stream = command_stream();
+    println!("{} commands, {} bytes, read in {} byte pieces", COMMANDS, stream.len(), READ_SIZE);
+
+    report("MessageReader::read", stream.len(), || {
+        let mut reader = MessageReader::new();
+        let mut frames = 0;
+        for piece in stream.chunks(READ_SIZE) {
+            frames += black_box(reader.read(piece)).len();
+        }
+        frames
+    });
+
+    report("MessageCodec::decode", stream.len(), || {
+        let mut codec = MessageCodec::new();
+        let mut buffer = BytesMut::new();
+        let mut frames = 0;
+        for piece in stream.chunks(READ_SIZE) {
+            buffer.extend_from_slice(piece);
+            while let Some(message) = codec.decode(&mut buffer).unwrap() {
+                black_box(message);
+                frames += 1;
+            }
+        }
+        frames
+    });
+}
+
+fn command_stream() -> Vec<u8> {
+    let commands = [
+        Command::Status,
+        Command::Collect { container: Some("web-1".to_string()) },
+        Command::SetInterval(Duration::from_secs(3600)),
+        Command::Pause,
+        Command::Resume,
+    ];
+    let mut stream = Vec::new();
+    for id in 0..COMMANDS {
+        let command = commands[id as usize % commands.len()].clone();
+        stream.extend(Message::from_request(&Request { id, command }).encode().unwrap());
+    }
+    stream
+}
+
+fn report(name: &str, bytes: usize, mut run: impl FnMut() -> usize) {
+    // Warm up once and check every frame came out
+    assert_eq!(run(), COMMANDS as usize);
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        run();
+    }
+    let per_round = start.elapsed() / ROUNDS;
+    let seconds = per_round.as_secs_f64();
+    println!(
+        "{:<22} {:>8.2} ms/stream {:>10.0} frames/s {:>8.1} MB/s",
+        name,
+        seconds * 1000.0,
+        COMMANDS as f64 / seconds,
+        bytes as f64 / seconds / 1_000_000.0
+    );
+}
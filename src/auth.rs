@@ -0,0 +1,135 @@
+/**
+ * Commands signed with a key shared by the logging server and whoever controls it. Each
+ * signature covers the request, the frame it is sent in, the time it was signed and a nonce,
+ * so a recorded command cannot be sent again later.
+ */
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::sync::Mutex;
+
+use crate::error::AuthError;
+use crate::message::FrameType;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// How far the signing time may be from the server's clock
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+// Longer nonces are refused so that the replay cache stays small
+const MAX_NONCE_LENGTH: usize = 64;
+
+#[derive(Clone)]
+pub struct CommandKey {
+    key: Arc<Vec<u8>>,
+}
+
+impl CommandKey {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        CommandKey { key: Arc::new(key.into()) }
+    }
+
+    // The whole file is the key, less any surrounding whitespace such as a trailing newline
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read(path).map_err(|e| anyhow::anyhow!("Reading command key {:?} failed: {}", path, e))?;
+        let key = contents.trim_ascii();
+        if key.is_empty() {
+            anyhow::bail!("Command key {:?} is empty", path);
+        }
+        Ok(CommandKey::new(key))
+    }
+
+    // Sent as "auth <timestamp> <nonce> <mac> <request>" in a frame of the given version and
+    // type, e.g. "auth 1753427098 9f86d081884c7d65... 4a5e1e4b... 7 pause"
+    pub fn sign(&self, version: u8, kind: FrameType, request: &str) -> anyhow::Result<String> {
+        let timestamp = unix_time();
+        let nonce = new_nonce()?;
+        let mac = self.mac(version, kind, timestamp, &nonce, request).finalize().into_bytes();
+        Ok(format!("auth {} {} {} {}", timestamp, nonce, hex(&mac), request))
+    }
+
+    // HMAC-SHA256 of "<version> <type> <timestamp> <nonce> <request>", sent in hex
+    fn mac(&self, version: u8, kind: FrameType, timestamp: u64, nonce: &str, request: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(format!("{} {} {} {} {}", version, kind as u8, timestamp, nonce, request).as_bytes());
+        mac
+    }
+}
+
+// Checks signed commands, remembering the nonces of recent ones
+#[derive(Clone)]
+pub struct CommandVerifier {
+    key: CommandKey,
+    // Nonces accepted within the clock skew, with the time they were signed
+    seen: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl CommandVerifier {
+    pub fn new(key: CommandKey) -> Self {
+        CommandVerifier { key, seen: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    // The request a signed command carries, once its signature, time and nonce check out.
+    // version and kind are those of the frame it arrived in.
+    pub async fn verify<'a>(&self, version: u8, kind: FrameType, text: &'a str) -> Result<&'a str, AuthError> {
+        let Some((timestamp, nonce, mac, request)) = split_signed(text) else {
+            return Err(if text.starts_with("auth ") { AuthError::Malformed } else { AuthError::Unsigned });
+        };
+        let timestamp: u64 = timestamp.parse().map_err(|_| AuthError::Malformed)?;
+        if nonce.len() > MAX_NONCE_LENGTH {
+            return Err(AuthError::Malformed);
+        }
+        let mac = unhex(mac).ok_or(AuthError::Malformed)?;
+        let now = unix_time();
+        let skew = now.abs_diff(timestamp);
+        if skew > MAX_CLOCK_SKEW.as_secs() {
+            return Err(AuthError::Expired { skew });
+        }
+        // Compared in constant time, so a signature cannot be guessed a byte at a time
+        if self.key.mac(version, kind, timestamp, nonce, request).verify_slice(&mac).is_err() {
+            return Err(AuthError::BadSignature);
+        }
+
+        let mut seen = self.seen.lock().await;
+        // Anything older is refused for its time, so need not be remembered
+        seen.retain(|_, signed| now.abs_diff(*signed) <= MAX_CLOCK_SKEW.as_secs());
+        if seen.insert(nonce.to_string(), timestamp).is_some() {
+            return Err(AuthError::Replayed);
+        }
+        Ok(request)
+    }
+}
+
+// The request a command carries, whether or not it is signed, e.g. to answer one that is refused
+pub fn request_text(text: &str) -> &str {
+    split_signed(text).map_or(text, |(_, _, _, request)| request)
+}
+
+fn split_signed(text: &str) -> Option<(&str, &str, &str, &str)> {
+    let mut parts = text.strip_prefix("auth ")?.splitn(4, ' ');
+    Some((parts.next()?, parts.next()?, parts.next()?, parts.next()?))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// Random, so that nonces cannot be predicted from earlier ones
+fn new_nonce() -> anyhow::Result<String> {
+    let mut nonce = [0u8; 16];
+    getrandom::getrandom(&mut nonce).map_err(|e| anyhow::anyhow!("Generating a nonce failed: {}", e))?;
+    Ok(hex(&nonce))
+}
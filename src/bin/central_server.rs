@@ -1,27 +1,65 @@
-use tokio::sync::Mutex;
-use std::sync::Arc;
+use std::path::PathBuf;
+
+use lib_setup::{auth::CommandKey, log_utils, central_state::{CentralState, start_http_server}, limits::ConnectionLimits, server::Server, transport, upload::UploadConfig};
+// Uploads are refused once they would leave less than this free on the log filesystem
+const DEFAULT_MIN_FREE_SPACE: u64 = 100 * 1024 * 1024;
 
-use lib_setup::{central_state::{CentralState, start_http_server}, server::Server};
 /**
  * Receives files from servers
  * Sends messages to servers
  */
 #[tokio::main]
 pub async fn main() -> anyhow::Result<()>{
+    // Logs are kept under Logs/ in the working directory unless given e.g. --log-dir /var/log/privacy_lock
+    if let Some(dir) = arg_path("--log-dir") {
+        log_utils::set_log_folder(&dir)?;
+    }
     // Shared state between TCP and HTTP
-    let state = CentralState{
-        logs: Arc::new(Mutex::new(Vec::new())),
-        servers: Arc::new(Mutex::new(Vec::new())),
-        running_containers: Arc::new(Mutex::new(Vec::new())),
-    };
+    let mut state = CentralState::new();
     let tcp_state = state.clone();
     // Establish TCP Server
-    let server = Server::new("0.0.0.0", 5000); // original port is 8080, changed to 5000 for multiple hosts
+    let mut server = Server::new("0.0.0.0", 5000) // original port is 8080, changed to 5000 for multiple hosts
+        .with_uploads(UploadConfig {
+            // Compressed reports are decompressed on arrival unless asked otherwise
+            store_compressed: std::env::args().any(|arg| arg == "--store-compressed"),
+            // Sizes in bytes, e.g. --max-file-size 104857600
+            max_file_size: arg_value("--max-file-size"),
+            max_host_daily_size: arg_value("--max-host-daily-size"),
+            min_free_space: arg_value("--min-free-space").unwrap_or(DEFAULT_MIN_FREE_SPACE),
+        })
+        .with_limits(ConnectionLimits {
+            max_connections: arg_value("--max-connections").map_or(ConnectionLimits::default().max_connections, |max| max as usize),
+            max_transfers: arg_value("--max-transfers").map_or(ConnectionLimits::default().max_transfers, |max| max as usize),
+            ..ConnectionLimits::default()
+        });
+    // Loggers connect over TLS when the server has a certificate, e.g.
+    // --tls-cert central.pem --tls-key central.key. With --tls-client-ca they must also present
+    // a certificate signed by that authority, and may only send reports for the host it names.
+    match (arg_path("--tls-cert"), arg_path("--tls-key")) {
+        (Some(cert), Some(key)) => {
+            let tls = match arg_path("--tls-client-ca") {
+                Some(client_ca) => transport::verifying_acceptor(&cert, &key, &client_ca)?,
+                None => transport::acceptor(&cert, &key)?,
+            };
+            server = server.with_tls(tls);
+        }
+        (None, None) => {}
+        _ => anyhow::bail!("--tls-cert and --tls-key must be given together"),
+    }
+    // Certificate trusted when fetching reports from loggers that listen over TLS
+    let pull_tls = arg_path("--tls-ca").map(|ca| transport::connector(&ca)).transpose()?;
+    // Key fetch commands are signed with, for loggers started with --command-key. Commands sent
+    // over a logger's own connection are signed with it too.
+    let pull_key = arg_path("--command-key").map(|path| CommandKey::from_file(&path)).transpose()?;
+    if let Some(key) = &pull_key {
+        state = state.with_command_key(key.clone());
+    }
 
     // Run both servers concurrently
     tokio::select! {
         result = server.run_storing_server(state.clone()) => result?,
-        _ = start_http_server(state) => {},
+        // Reports fetched through the HTTP API are stored like pushed ones
+        _ = start_http_server(state, server.uploads.clone(), pull_tls, pull_key) => {},
     }
 
     // Receive get request from client for number of running containers
@@ -29,4 +67,25 @@ pub async fn main() -> anyhow::Result<()>{
     // Respond to client with number of running containers
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Argument following a flag on the command line, e.g. "1000" for "--max-file-size 1000"
+fn arg(flag: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1)).cloned()
+}
+
+fn arg_path(flag: &str) -> Option<PathBuf> {
+    arg(flag).map(PathBuf::from)
+}
+
+fn arg_value(flag: &str) -> Option<u64> {
+    let value = arg(flag)?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            eprintln!("Ignoring {} {:?}, expected a number of bytes", flag, value);
+            None
+        }
+    }
+}
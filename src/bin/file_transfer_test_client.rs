@@ -1,5 +1,8 @@
 use std::env;
 use lib_setup::{client::Client, message::Message, log_utils, datetime};
+use lib_setup::progress::{TransferOptions, TransferProgress};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
 
 /*
     Sends commands to the server logger
@@ -8,7 +11,31 @@ use lib_setup::{client::Client, message::Message, log_utils, datetime};
 pub async fn main() -> anyhow::Result<()>{
     // Connect to server, send file
     let mut client = Client::connect("192.168.68.90", 5000).await?;
-    client.send_file("/home/leozl/Desktop/rust/privacy_lock/misc/testing_transfer_file_2.txt".to_string(), datetime::DateTime::now()).await?;
+    client.handshake().await?;
+
+    let (progress, mut updates) = watch::channel(TransferProgress::default());
+    tokio::spawn(async move {
+        while updates.changed().await.is_ok() {
+            let update = updates.borrow_and_update().clone();
+            println!(
+                "{}: {} of {} bytes ({:.0}%), {:.0} KB/s, {} left",
+                update.filename, update.sent, update.total, update.fraction() * 100.0,
+                update.throughput / 1024.0,
+                update.eta.map_or("unknown".to_string(), |eta| format!("{}s", eta.as_secs())),
+            );
+        }
+    });
+    // Ctrl-C stops the upload between chunks instead of cutting it off mid-frame
+    let cancel = CancellationToken::new();
+    let interrupted = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            interrupted.cancel();
+        }
+    });
+
+    let options = TransferOptions::new().with_progress(progress).with_cancel(cancel);
+    client.send_file_with("/home/leozl/Desktop/rust/privacy_lock/misc/testing_transfer_file_2.txt".to_string(), datetime::DateTime::now(), &options).await?;
 
     Ok(())
 }
\ No newline at end of file
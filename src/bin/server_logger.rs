@@ -1,52 +1,205 @@
-use tokio::time;
-use tokio::sync::Mutex;
+use std::path::PathBuf;
 use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::time;
 
+use lib_setup::auth::{CommandKey, CommandVerifier};
 use lib_setup::log_utils;
 use lib_setup::server::Server;
 use lib_setup::client::Client;
+use lib_setup::live::LiveStream;
+use lib_setup::logger_state::LoggerState;
+use lib_setup::message::{FrameType, LiveLine};
+use lib_setup::remote;
+use lib_setup::spool::{Backoff, Spool};
+use lib_setup::transport::{self, TlsConnector};
 
 /* Create a log file and send it to the central server */
 
+// How often the idle connection to the central server is checked
+const HEARTBEAT_INTERVAL: time::Duration = time::Duration::from_secs(60);
+// Failed deliveries are retried after a delay that doubles between these
+const RETRY_MIN_DELAY: time::Duration = time::Duration::from_secs(5);
+const RETRY_MAX_DELAY: time::Duration = time::Duration::from_secs(10 * 60);
+// Reports waiting for the central server beyond these are dropped, oldest first
+const SPOOL_MAX_BYTES: u64 = 512 * 1024 * 1024;
+const SPOOL_MAX_REPORTS: usize = 1000;
+// How often a paused logger checks whether it has been resumed
+const PAUSED_POLL_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()>{
-    // Mutex for starting and stopping logging
-    let running = Arc::new(Mutex::new(true)); // checked by daemon
-    let running_worker = Arc::clone(&running); // toggled by server
+    // Logs are kept under Logs/ in the working directory unless given e.g. --log-dir /var/log/privacy_lock
+    if let Some(dir) = arg_path("--log-dir") {
+        log_utils::set_log_folder(&dir)?;
+    }
+    // Running flag and interval, checked by daemon and toggled by server
+    let state = LoggerState::new();
+    let spool = Spool::new(log_utils::spool_dir(), SPOOL_MAX_BYTES, SPOOL_MAX_REPORTS);
+    // Wakes the forwarder when a report is spooled
+    let spooled = Arc::new(Notify::new());
+    // With --live every line logged is also sent to the central server as it is written
+    let live = std::env::args().any(|arg| arg == "--live").then(|| LiveStream::enable(log_utils::get_hostname()));
+    // Certificate trusted when connecting to a central server that listens over TLS, and the
+    // certificate naming this host for a central server that requires one
+    let central_tls = match (arg_path("--tls-ca"), arg_path("--tls-client-cert"), arg_path("--tls-client-key")) {
+        (Some(ca), Some(cert), Some(key)) => Some(transport::identified_connector(&ca, &cert, &key)?),
+        (Some(ca), None, None) => Some(transport::connector(&ca)?),
+        (None, None, None) => None,
+        _ => anyhow::bail!("--tls-client-cert and --tls-client-key must be given together, with --tls-ca"),
+    };
+
+    let mut server = Server::new("0.0.0.0", 8080);
+    // Commands are only accepted over TLS when the logger has a certificate
+    match (arg_path("--tls-cert"), arg_path("--tls-key")) {
+        (Some(cert), Some(key)) => server = server.with_tls(transport::acceptor(&cert, &key)?),
+        (None, None) => {}
+        _ => anyhow::bail!("--tls-cert and --tls-key must be given together"),
+    }
+    // Commands must be signed with the key in this file, which the controlling side shares
+    if let Some(path) = arg_path("--command-key") {
+        server = server.with_auth(CommandKey::from_file(&path)?);
+    }
 
     // 1st thread does logging and sleeping - daemon
     println!("Running logging and sleeping daemon...");
-    let logger_handle = tokio::spawn(sleep_logger(running));
+    let logger_handle = tokio::spawn(sleep_logger(state.clone(), spool.clone(), spooled.clone()));
+    // Reports are sent from the spool in the background, so an unreachable central server
+    // does not hold up logging. Commands the central server sends over the same connection are
+    // checked against the key the command port uses, sharing its record of nonces seen.
+    tokio::spawn(forward_reports(spool, spooled, live, central_tls, state.clone(), server.auth.clone()));
 
     // 2nd thread listens for commands and acts on them when receiving them
     println!("Running command listener server...");
-    let server = Server::new("0.0.0.0", 8080);
-    server.run_logging_server(running_worker).await?;
+    server.run_logging_server(state).await?;
 
     Ok(())
 }
 
 // First thread does logging and sleeping
-async fn sleep_logger(running: Arc<Mutex<bool>>){
-    let four_hours = time::Duration::from_secs(60*60*4);
+async fn sleep_logger(state: LoggerState, spool: Spool, spooled: Arc<Notify>){
     //let twenty_sec = time::Duration::from_secs(20);
-
     loop {
         // Check if should run
         let should_run = {
             // Mutex controlled by server
-            let running_guard = running.lock().await;
+            let running_guard = state.running.lock().await;
             *running_guard
         };
 
         if should_run{
-            // Log system and send logfile to central server
+            // Log system and queue the logfile for the central server
             let (fp, dt) = log_utils::log_system();
-            let mut client = Client::connect("127.0.0.1", 5000).await.unwrap();
-            client.send_file(fp, dt).await.unwrap();
-            time::sleep(four_hours).await;
+            match spool.add(&fp, &dt).await {
+                Ok(()) => spooled.notify_one(),
+                Err(e) => eprintln!("Failed to spool report {}: {}", fp, e),
+            }
+
+            let interval = *state.interval.lock().await;
+            time::sleep(interval).await;
         } else {
             println!("Paused");
+            time::sleep(PAUSED_POLL_INTERVAL).await;
+        }
+    }
+}
+
+// Second thread sends spooled reports in order, keeping one connection to the central
+// server open between them. Live lines are sent, and commands answered, over the same connection.
+async fn forward_reports(spool: Spool, spooled: Arc<Notify>, mut live: Option<LiveStream>, tls: Option<TlsConnector>, state: LoggerState, verifier: Option<CommandVerifier>) {
+    let mut central: Option<Client> = None;
+    let mut backoff = Backoff::new(RETRY_MIN_DELAY, RETRY_MAX_DELAY);
+
+    loop {
+        if let Err(e) = deliver_spooled(&spool, &mut central, live.is_some(), tls.as_ref()).await {
+            let delay = backoff.next_delay();
+            eprintln!("Sending spooled reports failed, retrying in {:?}: {}", delay, e);
+            central = None;
+            time::sleep(delay).await;
+            continue;
+        }
+        backoff.reset();
+
+        // Nothing left to send until the next report is spooled
+        match central.as_mut() {
+            Some(client) => {
+                if let Err(e) = serve_central(client, &spooled, &mut live, &state, verifier.as_ref()).await {
+                    eprintln!("Connection to central server lost: {}", e);
+                    central = None;
+                }
+            }
+            None => spooled.notified().await,
         }
     }
-}
\ No newline at end of file
+}
+
+// Sends every waiting report, oldest first, see Spool::deliver. Stops at the first report that
+// could not be delivered.
+async fn deliver_spooled(spool: &Spool, central: &mut Option<Client>, stay_connected: bool, tls: Option<&TlsConnector>) -> anyhow::Result<()> {
+    for report in spool.reports().await? {
+        let client = match central {
+            Some(client) => client,
+            None => central.insert(connect_central(tls).await?),
+        };
+        let delivered = spool.deliver(client, &report).await;
+        // Servers without typed frames close the connection after each file
+        if client.session.version < 4 {
+            *central = None;
+        }
+        delivered?;
+    }
+    // A streaming logger connects without waiting for a report, lines are sent as they are
+    // logged to a server that takes them. So does any logger the server can send commands to.
+    if central.is_none() {
+        let client = connect_central(tls).await?;
+        if (stay_connected && client.session.has("live")) || client.session.has("commands") {
+            *central = Some(client);
+        }
+    }
+    Ok(())
+}
+
+async fn connect_central(tls: Option<&TlsConnector>) -> anyhow::Result<Client> {
+    let mut client = Client::connect_with("127.0.0.1", 5000, tls).await?.with_commands();
+    client.handshake().await?;
+    Ok(client)
+}
+
+// Waits for the next report to be spooled, keeping the connection alive, streaming live lines
+// and answering commands
+async fn serve_central(client: &mut Client, spooled: &Notify, live: &mut Option<LiveStream>, state: &LoggerState, verifier: Option<&CommandVerifier>) -> anyhow::Result<()> {
+    let mut heartbeat = time::interval(HEARTBEAT_INTERVAL);
+    heartbeat.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = spooled.notified() => return Ok(()),
+            _ = heartbeat.tick() => client.heartbeat().await?,
+            Some(line) = next_live(live) => {
+                client.send_log_line(&line).await?;
+            }
+            message = client.recv() => {
+                let message = message?;
+                if message.kind == FrameType::Command {
+                    remote::answer(client, state, verifier, &message).await?;
+                } else {
+                    println!("Ignoring {:?} frame from the central server", message.kind);
+                }
+            }
+        }
+    }
+}
+
+// Never finishes when not streaming
+async fn next_live(live: &mut Option<LiveStream>) -> Option<LiveLine> {
+    match live {
+        Some(live) => live.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Path following a flag on the command line, e.g. "--tls-ca central.pem"
+fn arg_path(flag: &str) -> Option<PathBuf> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1)).map(PathBuf::from)
+}
@@ -1,5 +1,6 @@
 use std::env;
-use lib_setup::{client::Client, message::Message, log_utils::call_command};
+use std::path::Path;
+use lib_setup::{auth::CommandKey, client::Client, message::{Command, ReplyStatus}, transport};
 
 /*
     Sends commands to the server logger
@@ -7,24 +8,62 @@ use lib_setup::{client::Client, message::Message, log_utils::call_command};
 #[tokio::main]
 pub async fn main() -> anyhow::Result<()>{
     // Collect and process commands
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // Certificate trusted when the logger listens over TLS, given ahead of the command
+    let tls = take_flag(&mut args, "--tls-ca").map(|ca| transport::connector(Path::new(&ca))).transpose()?;
+    // File holding the key shared with a logger started with --command-key
+    let key = take_flag(&mut args, "--command-key").map(|path| CommandKey::from_file(Path::new(&path))).transpose()?;
     // If no commands are called
     if args.len() < 2 {
-        eprintln!("Usage: {} <COMMAND>", args[0]);
+        eprintln!("Usage: {} [--tls-ca <CERT>] [--command-key <FILE>] <COMMAND> [ARGS]", args[0]);
+        eprintln!("Commands: collect [CONTAINER], list, pause, resume, status, set_interval <SECONDS>");
         std::process::exit(1);
     }
-    let command = &args[1];
-    println!("Entered Command: {}", command);
-    if (command == "start") {
+    // Reject misspelled commands before connecting
+    let command = match Command::decode(&args[1..].join(" ")) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Entered Command: {:?}", command);
+    if command == Command::Resume {
         println!("Starting logging server / daemon");
     }
 
-    // Connect to server, feed command then exit
+    // Connect to server, feed command, wait for the reply then exit
     // localhost => 127.0.0.1
     // Miracle max => 198.12.64.18
     // My Laptop => 2403:5812:d483::1004 <===> 159.196.67.230
-    let mut client = Client::connect("192.168.68.90", 8080).await?;
-    client.send_message(Message::new(command)).await?;
-    client.send_message(Message::new("exit")).await?;
+    let mut client = Client::connect_with("192.168.68.90", 8080, tls.as_ref()).await?;
+    if let Some(key) = key {
+        client = client.with_key(key);
+    }
+    client.handshake().await?;
+    let reply = client.send_command(command).await?;
+    client.send_command(Command::Exit).await?;
+
+    // Exit status tells scripts whether the command worked
+    match reply.status {
+        ReplyStatus::Ok => println!("ok"),
+        ReplyStatus::Payload(data) => println!("{}", data),
+        ReplyStatus::Error(reason) => {
+            eprintln!("error: {}", reason);
+            std::process::exit(1);
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+// Removes a flag and the value following it from args, e.g. "--tls-ca logger.pem"
+fn take_flag(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == flag)?;
+    if index + 1 >= args.len() {
+        eprintln!("{} needs a file", flag);
+        std::process::exit(1);
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Some(value)
+}
@@ -1,10 +1,21 @@
+use std::convert::Infallible;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, mpsc, Mutex};
 use std::path::PathBuf;
 
 use warp::Filter;
 use warp::http::StatusCode;
+use warp::sse::Event;
 use tokio::fs;
+use tokio::net::TcpListener;
+
+use crate::auth::CommandKey;
+use crate::live::LiveLogs;
+use crate::message::{Command, ReplyStatus};
+use crate::remote::ConnectedLoggers;
+use crate::{log_utils, pull};
+use crate::transport::TlsConnector;
+use crate::upload::UploadConfig;
 
 #[derive(Clone)]
 pub struct CentralState {
@@ -12,11 +23,62 @@ pub struct CentralState {
     pub logs: Arc<Mutex<Vec<String>>>,
     pub servers: Arc<Mutex<Vec<String>>>,
     pub running_containers: Arc<Mutex<Vec<String>>>,
+    pub connections: Arc<Mutex<ConnectionStats>>,
+    // Lines streamed by loggers, see live.rs
+    pub live: LiveLogs,
+    // Loggers that take commands over their connection to the storing server, see remote.rs
+    pub loggers: ConnectedLoggers,
 }
 
-pub async fn start_http_server(state: CentralState) {
-    // Create routes
+impl CentralState {
+    pub fn new() -> Self {
+        CentralState {
+            logs: Arc::new(Mutex::new(Vec::new())),
+            servers: Arc::new(Mutex::new(Vec::new())),
+            running_containers: Arc::new(Mutex::new(Vec::new())),
+            connections: Arc::new(Mutex::new(ConnectionStats::default())),
+            live: LiveLogs::new(),
+            loggers: ConnectedLoggers::new(),
+        }
+    }
+
+    // Commands sent to connected loggers are signed with key
+    pub fn with_command_key(mut self, key: CommandKey) -> Self {
+        self.loggers = self.loggers.with_key(key);
+        self
+    }
+}
+
+impl Default for CentralState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// What the storing server did with the connections it accepted, served at GET /stats
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats {
+    pub accepted: u64,
+    // Turned away because max_connections were already being served
+    pub rejected_busy: u64,
+    // Turned away because their address connected too often
+    pub rejected_rate_limited: u64,
+    // Files refused because max_transfers were already being received
+    pub rejected_transfers: u64,
+    pub timed_out: u64,
+}
+
+// Reports are fetched from loggers over TLS when given a connector, and with signed commands
+// when given a key
+pub async fn start_http_server(state: CentralState, uploads: UploadConfig, tls: Option<TlsConnector>, key: Option<CommandKey>) {
     println!("Starting HTTP server on 0.0.0.0:3030");
+    let listener = TcpListener::bind(("0.0.0.0", 3030)).await.expect("failed to bind the HTTP port");
+    start_http_server_on(listener, state, uploads, tls, key).await;
+}
+
+// Serves the HTTP API on a listener that is already bound
+pub async fn start_http_server_on(listener: TcpListener, state: CentralState, uploads: UploadConfig, tls: Option<TlsConnector>, key: Option<CommandKey>) {
+    // Create routes
     // GET /logs - retrieve all logs
     let get_logs = warp::path("logs")
         .and(warp::get())
@@ -36,6 +98,34 @@ pub async fn start_http_server(state: CentralState) {
         .and(with_state(state.clone()))
         .and_then(post_logs_handler);
     
+    // POST /collect - fetch a fresh report from a logger now
+    let collect = warp::path("collect")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(warp::any().map(move || uploads.clone()))
+        .and(warp::any().map(move || tls.clone()))
+        .and(warp::any().map(move || key.clone()))
+        .and_then(collect_handler);
+
+    // POST /command - send a command to a logger over its connection to the storing server
+    let command = warp::path("command")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(command_handler);
+
+    // GET /stats - connection counts of the storing server
+    let get_stats = warp::path("stats")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(get_stats_handler);
+
+    // GET /live/<host> - lines streamed by a logger as they arrive, as server-sent events
+    let live = warp::path!("live" / String)
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(live_handler);
+
     // GET /health - health check
     let health = warp::path("health")
         .map(|| "Server is running");
@@ -44,16 +134,20 @@ pub async fn start_http_server(state: CentralState) {
     let routes = get_logs
         .or(get_servers)
         .or(post_logs)
+        .or(collect)
+        .or(command)
+        .or(get_stats)
+        .or(live)
         .or(health)
         .with(warp::cors().allow_any_origin());
 
     //println!("HTTP server listening on 127.0.0.1:3030");
-    warp::serve(routes).run(([0, 0, 0, 0], 3030)).await;
+    warp::serve(routes).incoming(listener).run().await;
 }
 
 // Handler for GET /logs
 async fn get_logs_handler(_state: CentralState) -> Result<impl warp::Reply, warp::Rejection> {
-    match read_log_files("Logs").await {
+    match read_log_files(&log_utils::get_log_folder()).await {
         Ok(files) => {
             Ok(warp::reply::with_status(
                 warp::reply::json(&files),
@@ -105,9 +199,7 @@ async fn post_logs_handler(
 
 // Handler for GET /servers -> returns json content of servers.json
 async fn get_servers_handler(_state: CentralState) -> Result<impl warp::Reply, warp::Rejection> {
-    let servers_file = "Logs/servers.json";
-    
-    match fs::read_to_string(servers_file).await {
+    match fs::read_to_string(log_utils::servers_file()).await {
         Ok(content) => {
             let json: serde_json::Value = serde_json::from_str(&content).unwrap_or(serde_json::json!({}));
             Ok(warp::reply::with_status(
@@ -126,6 +218,154 @@ async fn get_servers_handler(_state: CentralState) -> Result<impl warp::Reply, w
     }
 }
 
+// Handler for POST /collect, e.g. {"host": "web-1", "container": "nginx"}. Only hosts in
+// servers.json are fetched from, on the logger command port. That limits where the endpoint
+// connects to less than it seems: a host is added there by the name before "||" in any report
+// the storing server accepts, so unless it requires client certificates (--tls-client-ca),
+// anyone who can upload a report can name a host for it to connect to. What comes back is only
+// stored when it is a report for the host that was asked.
+async fn collect_handler(body: serde_json::Value, uploads: UploadConfig, tls: Option<TlsConnector>, key: Option<CommandKey>) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(host) = body.get("host").and_then(|v| v.as_str()) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "status": "error",
+                "message": "A host is required"
+            })),
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+    if !known_servers().await.iter().any(|known| known == host) {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "status": "error",
+                "message": format!("{} is not a known server", host)
+            })),
+            StatusCode::FORBIDDEN,
+        ));
+    }
+    let port = pull::LOGGER_COMMAND_PORT;
+    let container = body.get("container").and_then(|v| v.as_str()).map(|name| name.to_string());
+
+    match pull::fetch_report(host, port, container, &uploads, tls.as_ref(), key).await {
+        Ok(path) => Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "status": "ok",
+                "path": path.to_string_lossy()
+            })),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            println!("Fetching a report from {}:{} failed: {}", host, port, e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "status": "error",
+                    "message": e.to_string()
+                })),
+                StatusCode::BAD_GATEWAY,
+            ))
+        }
+    }
+}
+
+// Handler for POST /command, e.g. {"host": "web-1", "command": "pause"}. host is the name in the
+// logger's client certificate, or the address it connected from when the storing server does
+// not require certificates.
+async fn command_handler(body: serde_json::Value, state: CentralState) -> Result<impl warp::Reply, warp::Rejection> {
+    let (Some(host), Some(command)) = (body.get("host").and_then(|v| v.as_str()), body.get("command").and_then(|v| v.as_str())) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "status": "error",
+                "message": "A host and a command are required"
+            })),
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+    let command = match Command::decode(command) {
+        Ok(command) => command,
+        Err(e) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "status": "error",
+                    "message": e.to_string()
+                })),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+    if !state.loggers.hosts().await.iter().any(|connected| connected == host) {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "status": "error",
+                "message": format!("{} is not connected", host)
+            })),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+
+    let (body, status) = match state.loggers.send(host, command).await {
+        Ok(reply) => match reply.status {
+            ReplyStatus::Ok => (serde_json::json!({ "status": "ok" }), StatusCode::OK),
+            ReplyStatus::Payload(payload) => (serde_json::json!({ "status": "ok", "payload": payload }), StatusCode::OK),
+            ReplyStatus::Error(message) => (serde_json::json!({ "status": "error", "message": message }), StatusCode::BAD_GATEWAY),
+        },
+        Err(e) => {
+            println!("Sending a command to {} failed: {}", host, e);
+            (serde_json::json!({ "status": "error", "message": e.to_string() }), StatusCode::BAD_GATEWAY)
+        }
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&body), status))
+}
+
+// Handler for GET /stats
+async fn get_stats_handler(state: CentralState) -> Result<impl warp::Reply, warp::Rejection> {
+    let stats = state.connections.lock().await.clone();
+    Ok(warp::reply::json(&serde_json::json!({
+        "accepted": stats.accepted,
+        "rejected": stats.rejected_busy + stats.rejected_rate_limited,
+        "rejected_busy": stats.rejected_busy,
+        "rejected_rate_limited": stats.rejected_rate_limited,
+        "rejected_transfers": stats.rejected_transfers,
+        "timed_out": stats.timed_out,
+    })))
+}
+
+// Handler for GET /live/<host>. Each line is a "line" event, and a watcher that falls too far
+// behind is sent a "skipped" event with the number of lines it missed.
+fn live_handler(host: String, state: CentralState) -> impl warp::Reply {
+    let mut lines = state.live.subscribe();
+    let (events, mut watcher) = mpsc::channel::<Event>(1);
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                // The watcher went away
+                _ = events.closed() => break,
+                line = lines.recv() => match line {
+                    Ok(line) if line.host == host => Event::default().event("line").data(line.line),
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => Event::default().event("skipped").data(missed.to_string()),
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            };
+            if events.send(event).await.is_err() {
+                break;
+            }
+        }
+    });
+    let stream = futures::stream::poll_fn(move |cx| watcher.poll_recv(cx).map(|event| event.map(Ok::<_, Infallible>)));
+    warp::sse::reply(warp::sse::keep_alive().stream(stream))
+}
+
+// Hosts that have sent a report, the keys of servers.json
+async fn known_servers() -> Vec<String> {
+    let Ok(content) = fs::read_to_string(log_utils::servers_file()).await else {
+        return Vec::new();
+    };
+    match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&content) {
+        Ok(servers) => servers.keys().cloned().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
 // Helper to pass state to handlers
 fn with_state(state: CentralState) -> impl Filter<Extract = (CentralState,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || state.clone())
@@ -1,40 +1,378 @@
 use tokio::fs::{File, self};
-use tokio::io::{AsyncWriteExt, AsyncReadExt, BufWriter, BufReader};
-use tokio::net::TcpStream;
 
 use crate::file_info::FileInfo;
-use crate::message::Message;
-use crate::log_utils;
+use futures::{SinkExt, StreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Cursor, SeekFrom};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tokio::time::timeout;
+use tokio_util::codec::{Framed, FramedWrite};
+use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::auth::CommandKey;
+use crate::codec::{FileFrame, FileInfoCodec, MessageCodec};
+use crate::compression::Compression;
+use crate::handshake::{Hello, Session};
+use crate::error::{ConnectionClosed, ReplyTimedOut, TransferCancelled, UploadLimit, UploadLimitExceeded, UploadRejected};
+use crate::message::{Command, FileResult, FrameType, LiveLine, Message, Reply, ReplyStatus, Request};
+use crate::progress::{ProgressTracker, TransferOptions};
+use crate::transport::{Connection, TlsConnector};
 use crate::datetime;
 
 const CHUNK_SIZE: usize = 100_000;
 
+// What send_file streams, the file itself or a compressed copy of it in memory
+trait FileBody: AsyncRead + AsyncSeek + Unpin + Send {}
+impl<T: AsyncRead + AsyncSeek + Unpin + Send> FileBody for T {}
+
+// A file ready to be sent, with what the session lets the server check about it
+pub(crate) struct Upload {
+    file_info: FileInfo,
+    body: Box<dyn FileBody>,
+    acked: bool,
+    resumable: bool,
+}
+
+impl Upload {
+    // Header and body in typed frames without waiting on the receiver, for one that neither
+    // resumes nor acknowledges files, e.g. the central server pulling a report
+    pub(crate) async fn send_unacknowledged(self, frames: &mut Framed<Connection, MessageCodec>, version: u8) -> anyhow::Result<u64> {
+        frames.send(Message::with_kind(FrameType::FileHeader, self.file_info.encode()).with_version(version)).await?;
+        let options = TransferOptions::default();
+        let tracker = ProgressTracker::new(&options, &self.file_info.filename, self.file_info.f_len, 0);
+        Upload::send_chunks(frames, self.body, version, &tracker, options.cancel_token(), false).await
+    }
+
+    // A cancelled transfer ends with a file ack from the sender when the receiver understands
+    // one, otherwise the receiver is left expecting the rest of the file
+    async fn send_chunks(
+        frames: &mut Framed<Connection, MessageCodec>,
+        body: Box<dyn FileBody>,
+        version: u8,
+        tracker: &ProgressTracker<'_>,
+        cancel: &CancellationToken,
+        can_cancel: bool,
+    ) -> anyhow::Result<u64> {
+        let mut sent = 0;
+        let mut chunks = ReaderStream::with_capacity(body, CHUNK_SIZE);
+        loop {
+            let chunk = tokio::select! {
+                // Checked first, so that no chunk is sent once the token is cancelled
+                biased;
+                _ = cancel.cancelled() => {
+                    if can_cancel {
+                        let status = ReplyStatus::Error("cancelled".to_string());
+                        frames.send(Message::file_ack(&status).with_version(version)).await?;
+                    }
+                    return Err(tracker.cancelled(sent).into());
+                }
+                chunk = chunks.next() => chunk,
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            let chunk = chunk?;
+            sent += chunk.len() as u64;
+            frames.send(Message::with_kind(FrameType::FileChunk, chunk).with_version(version)).await?;
+            tracker.update(sent);
+        }
+        Ok(sent)
+    }
+}
+// Servers that predate replies never answer a hello
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+// Collecting a report shells out to lxc for every container, so replies are given a while
+pub const DEFAULT_REPLY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
 pub struct Client {
     pub server_host: String,
     pub server_port: u16,
-    pub stream: TcpStream,
+    pub stream: Framed<Connection, MessageCodec>,
+    // Legacy until handshake() agrees on something newer
+    pub session: Session,
+    next_id: u32,
+    // Replies that arrived while waiting for a different id
+    pending: HashMap<u32, Reply>,
+    // Frames the server sent on its own, e.g. the report pulled with a fetch command
+    unsolicited: VecDeque<Message>,
+    // Commands are signed when set, for servers that require it
+    key: Option<CommandKey>,
+    // How long wait_reply() waits for the answer to a command
+    reply_timeout: Duration,
+    // Offers "commands" in the handshake, see with_commands
+    answers_commands: bool,
 }
 
 impl Client {
     pub async fn connect(host: impl Into<String>, port: u16) -> anyhow::Result<Self> {
+        Client::connect_with(host, port, None).await
+    }
+
+    // connect over TLS when given a connector, see transport::connector
+    pub async fn connect_with(host: impl Into<String>, port: u16, tls: Option<&TlsConnector>) -> anyhow::Result<Self> {
         let host = host.into();
-        let address = format!("{}:{}", host, port);
-        let stream = TcpStream::connect(address).await?;
+        let stream = Connection::connect(&host, port, tls).await?;
 
         Ok(Self {
             server_host: host.into(),
             server_port: port,
-            stream,
+            stream: Framed::new(stream, MessageCodec::new()),
+            session: Session::legacy(),
+            next_id: 1,
+            pending: HashMap::new(),
+            unsolicited: VecDeque::new(),
+            key: None,
+            reply_timeout: DEFAULT_REPLY_TIMEOUT,
+            answers_commands: false,
+        })
+    }
+
+    pub fn with_key(mut self, key: CommandKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn with_reply_timeout(mut self, reply_timeout: Duration) -> Self {
+        self.reply_timeout = reply_timeout;
+        self
+    }
+
+    // Offers to answer commands the central server sends over this connection. They arrive
+    // through recv() and are answered with remote::answer.
+    pub fn with_commands(mut self) -> Self {
+        self.answers_commands = true;
+        self
+    }
+
+    // Agrees on a protocol version with the server, falling back to the legacy protocol
+    // when the server does not understand the handshake
+    pub async fn handshake(&mut self) -> anyhow::Result<Session> {
+        let mut local = Hello::local();
+        if !self.answers_commands {
+            local.capabilities.retain(|c| c != "commands");
+        }
+        self.stream.send(local.to_message()).await?;
+
+        let session = match timeout(HANDSHAKE_TIMEOUT, self.next_message()).await {
+            Ok(Ok(message)) => match Hello::from_message(&message) {
+                Some(answer) => local.negotiate(&answer)?,
+                // Servers without a handshake answer it with an error reply, so do reply
+                None => Session { capabilities: vec!["replies".to_string()], ..Session::legacy() },
+            },
+            // Storing servers that predate the handshake take the hello for a file header they
+            // do not recognise and close the connection, so a new one is opened without it.
+            // They never spoke TLS.
+            Ok(Err(e)) if Client::closed_by_peer(&e) && matches!(self.stream.get_ref(), Connection::Plain(_)) => {
+                println!("{}:{} closed the connection on the handshake, continuing without one", self.server_host, self.server_port);
+                let stream = Connection::connect(&self.server_host, self.server_port, None).await?;
+                self.stream = Framed::new(stream, MessageCodec::new());
+                Session::legacy()
+            }
+            Ok(Err(e)) => return Err(e),
+            // Servers that predate replies read the hello as a command they do not recognise
+            Err(_) => Session::legacy(),
+        };
+        self.session = session.clone();
+        Ok(session)
+    }
+
+    // The server hung up, as opposed to sending something that could not be read
+    fn closed_by_peer(e: &anyhow::Error) -> bool {
+        if e.is::<ConnectionClosed>() {
+            return true;
+        }
+        e.downcast_ref::<std::io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::UnexpectedEof
+            )
         })
     }
 
     pub async fn send_message(&mut self, message: Message) -> anyhow::Result<()> {
-        self.stream.write_all(&message.encode()).await?;
+        self.stream.send(message.with_version(self.session.version)).await?;
         Ok(())
     }
 
+    // Sends a command and waits for the server's reply to it. Servers that predate replies
+    // never answer, so a command sent to one is taken as done once it is sent.
+    pub async fn send_command(&mut self, command: Command) -> anyhow::Result<Reply> {
+        let id = self.send_request(command).await?;
+        if !self.session.has("replies") {
+            return Ok(Reply { id, status: ReplyStatus::Ok });
+        }
+        self.wait_reply(id).await
+    }
+
+    // Sends a command without waiting, returns the id its reply will carry. Servers that predate
+    // replies are sent the command's original name, without an id or signature, and return 0.
+    pub async fn send_request(&mut self, command: Command) -> anyhow::Result<u32> {
+        if !self.session.has("replies") {
+            let Some(name) = command.legacy_name() else {
+                anyhow::bail!("{}:{} predates replies and has no {:?} command", self.server_host, self.server_port, command);
+            };
+            self.send_message(Message::new(name)).await?;
+            return Ok(0);
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        let mut message = Message::from_request(&Request { id, command });
+        if let Some(key) = &self.key {
+            message = Message::new(key.sign(self.session.version, FrameType::Command, message.text())?);
+        }
+        self.send_message(message).await?;
+        Ok(id)
+    }
+
+    // The reply to command id, or ReplyTimedOut when it has not arrived within the reply timeout
+    pub async fn wait_reply(&mut self, id: u32) -> anyhow::Result<Reply> {
+        let after = self.reply_timeout;
+        match timeout(after, self.next_reply(id)).await {
+            Ok(reply) => reply,
+            Err(_) => Err(ReplyTimedOut { id, after }.into()),
+        }
+    }
+
+    async fn next_reply(&mut self, id: u32) -> anyhow::Result<Reply> {
+        loop {
+            if let Some(reply) = self.pending.remove(&id) {
+                return Ok(reply);
+            }
+            let message = self.next_message().await?;
+            self.route(message)?;
+        }
+    }
+
+    // Sends a heartbeat and waits for the server to answer it
+    pub async fn heartbeat(&mut self) -> anyhow::Result<()> {
+        self.send_message(Message::heartbeat()).await?;
+        self.wait_for(&[FrameType::Heartbeat]).await?;
+        Ok(())
+    }
+
+    // Streams one line of the log being written. Dropped when the server did not agree to
+    // take live lines, returning whether it was sent.
+    pub async fn send_log_line(&mut self, line: &LiveLine) -> anyhow::Result<bool> {
+        if self.session.version < 4 || !self.session.has("live") {
+            return Ok(false);
+        }
+        self.send_message(Message::log_line(line)).await?;
+        Ok(true)
+    }
+
+    // Next frame of one of the given kinds, routing everything before it
+    async fn wait_for(&mut self, kinds: &[FrameType]) -> anyhow::Result<Message> {
+        loop {
+            let message = self.next_message().await?;
+            if kinds.contains(&message.kind) {
+                return Ok(message);
+            }
+            self.route(message)?;
+        }
+    }
+
+    // Next frame the server sent that is not an answer to this client
+    pub async fn recv(&mut self) -> anyhow::Result<Message> {
+        match self.unsolicited.pop_front() {
+            Some(message) => Ok(message),
+            None => self.next_message().await,
+        }
+    }
+
+    // Keeps replies for wait_reply() and everything else for recv()
+    fn route(&mut self, message: Message) -> anyhow::Result<()> {
+        // Frames before version 4 carry no type, the server only ever sends replies on them
+        if message.kind == FrameType::Reply || self.session.version < 4 {
+            let reply = message.to_reply()?;
+            self.pending.insert(reply.id, reply);
+        } else if message.kind != FrameType::Heartbeat {
+            self.unsolicited.push_back(message);
+        }
+        Ok(())
+    }
+
+    async fn next_message(&mut self) -> anyhow::Result<Message> {
+        let message = self.stream.next().await;
+        for e in self.stream.codec_mut().take_errors() {
+            eprintln!("Dropped frame from server: {}", e);
+        }
+        match message {
+            Some(message) => message,
+            None => Err(ConnectionClosed.into()),
+        }
+    }
+
     pub async fn send_file(&mut self, file_path: String, datetime: datetime::DateTime) -> anyhow::Result<()> {
-        let meta = fs::metadata(file_path.clone()).await.expect("File not valid");
+        self.send_file_with(file_path, datetime, &TransferOptions::default()).await
+    }
+
+    // send_file that reports its progress and can be cancelled from another task. A cancelled
+    // transfer returns TransferCancelled. The connection stays usable when the server offered
+    // "cancel", otherwise it should be dropped.
+    pub async fn send_file_with(&mut self, file_path: String, datetime: datetime::DateTime, options: &TransferOptions) -> anyhow::Result<()> {
+        let upload = Client::prepare_upload(&self.session, file_path, datetime).await?;
+        if options.cancel_token().is_cancelled() {
+            let file_info = &upload.file_info;
+            return Err(TransferCancelled { filename: file_info.filename.clone(), sent: 0, total: file_info.f_len }.into());
+        }
+        self.send_upload(upload, options).await
+    }
+
+    // Sends every file over this connection and reports what happened to each. A file that
+    // cannot be read or that the server rejects does not stop the rest, a lost connection does.
+    pub async fn send_files(&mut self, files: Vec<(String, datetime::DateTime)>) -> anyhow::Result<Vec<FileResult>> {
+        let mut results = Vec::new();
+        // Indexes into results of the files the server was sent, in order
+        let mut sent = Vec::new();
+
+        for (file_path, datetime) in files {
+            let filename = file_path.split("/").last().unwrap_or("").to_string();
+            let status = match Client::prepare_upload(&self.session, file_path, datetime).await {
+                Ok(upload) => {
+                    sent.push(results.len());
+                    match self.send_upload(upload, &TransferOptions::default()).await {
+                        Ok(()) => ReplyStatus::Ok,
+                        Err(e) => {
+                            if let Some(exceeded) = e.downcast_ref::<UploadLimitExceeded>() {
+                                ReplyStatus::Error(exceeded.limit.encode())
+                            } else if let Some(rejected) = e.downcast_ref::<UploadRejected>() {
+                                ReplyStatus::Error(rejected.encode())
+                            } else {
+                                return Err(e);
+                            }
+                        }
+                    }
+                }
+                Err(e) => ReplyStatus::Error(e.to_string()),
+            };
+            results.push(FileResult { filename, status });
+        }
+
+        // The server's account of the batch is the final word on the files it was sent
+        if self.session.version >= 4 && self.session.has("batch") {
+            self.send_message(Message::batch_end(&[])).await?;
+            let mut stored = self.wait_for(&[FrameType::BatchEnd]).await?.to_batch_results()?;
+            if stored.len() < sent.len() {
+                anyhow::bail!("Server reported {} files for a batch of {}", stored.len(), sent.len());
+            }
+            // Files sent one at a time since the last batch are reported first
+            let batch = stored.split_off(stored.len() - sent.len());
+            for (index, result) in sent.into_iter().zip(batch) {
+                results[index].status = result.status;
+            }
+        }
+
+        Ok(results)
+    }
+
+    // Reads, compresses and hashes a file before anything is sent
+    pub(crate) async fn prepare_upload(session: &Session, file_path: String, datetime: datetime::DateTime) -> anyhow::Result<Upload> {
+        let meta = fs::metadata(&file_path)
+            .await
+            .map_err(|e| anyhow::anyhow!("File not valid: {:?}: {}", file_path, e))?;
         if !meta.is_file() {
             anyhow::bail!("path is not a file: {:?}", file_path);
         }
@@ -46,29 +384,149 @@ impl Client {
             .unwrap()
             .to_string();
 
-        // Header: 1 byte type + 8 bytes length (big endian) + 2 bytes name length + name bytes
-        // Work to package this as a struct
-        let mut writer = BufWriter::new(&mut self.stream);
-        let file_info: FileInfo = FileInfo::new(file_len, filename, datetime);
-        writer.write_all(&file_info.encode()).await?;
-        writer.flush().await?;
+        // Compressed in memory up front, so the digest and resume offsets refer to what is sent
+        let compression = Client::compression(session);
+        let (mut body, body_len): (Box<dyn FileBody>, u64) = if compression == Compression::None {
+            (Box::new(File::open(&file_path).await?), file_len)
+        } else {
+            let raw = fs::read(&file_path).await?;
+            let compressed = tokio::task::spawn_blocking(move || compression.compress(&raw)).await??;
+            let compressed_len = compressed.len() as u64;
+            (Box::new(Cursor::new(compressed)), compressed_len)
+        };
 
-        // Stream file bytes
-        let file = File::open(file_path).await?;
-        let mut reader = BufReader::new(file);
-        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut file_info: FileInfo = FileInfo::new(body_len, filename, datetime);
+        if compression != Compression::None {
+            file_info = file_info.with_compression(compression, file_len);
+        }
+        // Receivers of typed frames check files against the digest
+        let acked = session.version >= 4 && session.has("file_ack");
+        // and servers that resume uploads recognise a retry by its upload id
+        let resumable = session.version >= 4 && session.has("resume");
+        if session.version >= 4 {
+            let digest = Client::body_sha256(&mut body).await?;
+            if resumable {
+                let upload_id = Client::upload_id(&file_info.filename, &digest);
+                file_info = file_info.with_upload_id(upload_id);
+            }
+            file_info = file_info.with_sha256(digest);
+        }
+
+        Ok(Upload { file_info, body, acked, resumable })
+    }
+
+    async fn send_upload(&mut self, upload: Upload, options: &TransferOptions) -> anyhow::Result<()> {
+        let Upload { file_info, mut body, acked, resumable } = upload;
         let mut sent: u64 = 0;
+        // Bytes the server kept from an earlier attempt
+        let mut resumed_from: u64 = 0;
+
+        if self.session.version >= 4 {
+            // Typed frames, so the connection stays usable afterwards
+            self.send_message(Message::with_kind(FrameType::FileHeader, file_info.encode())).await?;
+            if resumable {
+                // A header the server refuses outright is answered with an ack instead
+                let answer = self.wait_for(&[FrameType::FileResume, FrameType::FileAck]).await?;
+                if answer.kind == FrameType::FileAck {
+                    return Err(Client::rejection(&file_info, answer.to_file_ack()?));
+                }
+                let offset = answer.to_file_resume()?;
+                if offset > file_info.f_len {
+                    anyhow::bail!("Server holds {} bytes of a {} byte file", offset, file_info.f_len);
+                }
+                if offset > 0 {
+                    println!("Resuming {} from byte {}", file_info.filename, offset);
+                }
+                body.seek(SeekFrom::Start(offset)).await?;
+                resumed_from = offset;
+            }
+            let tracker = ProgressTracker::new(options, &file_info.filename, file_info.f_len, resumed_from);
+            let can_cancel = self.session.has("cancel");
+            sent = Upload::send_chunks(&mut self.stream, body, self.session.version, &tracker, options.cancel_token(), can_cancel).await?;
+        } else {
+            // Header: 1 byte type + 8 bytes length (big endian) + 2 bytes name length + name bytes
+            let mut writer = FramedWrite::new(self.stream.get_mut(), FileInfoCodec::new());
+            writer.send(FileFrame::Header(file_info.clone())).await?;
+
+            // Stream file bytes
+            let tracker = ProgressTracker::new(options, &file_info.filename, file_info.f_len, 0);
+            let mut chunks = ReaderStream::with_capacity(body, CHUNK_SIZE);
+            loop {
+                let chunk = tokio::select! {
+                    biased;
+                    _ = options.cancel_token().cancelled() => return Err(tracker.cancelled(sent).into()),
+                    chunk = chunks.next() => chunk,
+                };
+                let Some(chunk) = chunk else {
+                    break;
+                };
+                let chunk = chunk?;
+                sent += chunk.len() as u64;
+                writer.send(FileFrame::Chunk(chunk)).await?;
+                tracker.update(sent);
+            }
+        }
+        if file_info.compression == Compression::None {
+            println!("Sent {} bytes", sent);
+        } else {
+            println!("Sent {} bytes ({} compressed from {} bytes)", sent, file_info.compression.name(), file_info.original_len);
+        }
+
+        if acked {
+            match self.wait_for(&[FrameType::FileAck]).await?.to_file_ack()? {
+                ReplyStatus::Ok => println!("Server stored {}", file_info.filename),
+                status => return Err(Client::rejection(&file_info, status)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // An UploadLimitExceeded when the server refused the file under one of its limits,
+    // otherwise an UploadRejected
+    fn rejection(file_info: &FileInfo, status: ReplyStatus) -> anyhow::Error {
+        let filename = file_info.filename.clone();
+        let reason = match status {
+            ReplyStatus::Ok => "accepted without being sent".to_string(),
+            ReplyStatus::Error(reason) | ReplyStatus::Payload(reason) => reason,
+        };
+        match UploadLimit::decode(&reason) {
+            Some(limit) => UploadLimitExceeded { filename, limit }.into(),
+            None => UploadRejected::decode(filename, &reason).into(),
+        }
+    }
+
+    // Leaves the body where it started
+    async fn body_sha256(body: &mut Box<dyn FileBody>) -> anyhow::Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; CHUNK_SIZE];
         loop {
-            let n = reader.read(&mut buffer).await?;
+            let n = body.read(&mut buffer).await?;
             if n == 0 {
                 break;
             }
-            writer.write_all(&buffer[..n]).await?;
-            sent += n as u64;
+            hasher.update(&buffer[..n]);
         }
-        writer.flush().await?;
-        println!("Sent {} bytes", sent);
+        body.seek(SeekFrom::Start(0)).await?;
+        Ok(hasher.finalize().into())
+    }
 
-        Ok(())
+    // Best compression both sides support, none for peers without typed frames
+    fn compression(session: &Session) -> Compression {
+        if session.version < 4 {
+            return Compression::None;
+        }
+        [Compression::Zstd, Compression::Gzip]
+            .into_iter()
+            .find(|compression| session.has(compression.name()))
+            .unwrap_or(Compression::None)
+    }
+
+    // Same file, same id, so a retry after a dropped connection finds the earlier attempt
+    fn upload_id(filename: &str, digest: &[u8; 32]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(filename.as_bytes());
+        hasher.update(digest);
+        hasher.finalize()[..16].iter().map(|b| format!("{:02x}", b)).collect()
     }
 }
\ No newline at end of file
@@ -0,0 +1,152 @@
+/**
+ * tokio-util codecs for the command protocol and the file transfer header,
+ * for use with Framed, FramedRead and FramedWrite
+ */
+use bytes::{Buf, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::constants::DEFAULT_MAX_FRAME_SIZE;
+use crate::error::FrameError;
+use crate::file_info::FileInfo;
+use crate::message::Message;
+use crate::message_reader::{next_step, Step};
+
+// Body bytes are handed out in pieces of at most this size
+const CHUNK_SIZE: usize = 100_000;
+
+// Frames Message values, skipping corrupt frames the same way MessageReader does
+pub struct MessageCodec {
+    pub max_frame_size: usize,
+    pub dropped_frames: u64,
+    pub dropped_bytes: u64,
+    errors: Vec<FrameError>,
+}
+
+// A file transfer is a header followed by the raw bytes of the file
+#[derive(Debug)]
+pub enum FileFrame {
+    Header(FileInfo),
+    Chunk(Bytes),
+}
+
+// Decodes a header, then hands out the following f_len bytes as chunks
+pub struct FileInfoCodec {
+    // Body bytes of the current file still to come
+    remaining: u64,
+}
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        Self::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            dropped_frames: 0,
+            dropped_bytes: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn take_errors(&mut self) -> Vec<FrameError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    fn drop_frame(&mut self, error: FrameError, length: usize) {
+        self.dropped_frames += 1;
+        self.dropped_bytes += length as u64;
+        self.errors.push(error);
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Self::Error> {
+        loop {
+            match next_step(src, self.max_frame_size) {
+                Step::Frame(length) => {
+                    let frame = src.split_to(length).freeze();
+                    match Message::decode_frame(frame) {
+                        Ok(message) => return Ok(Some(message)),
+                        Err(e) => self.drop_frame(e, length),
+                    }
+                }
+                Step::Skip { length, error } => {
+                    src.advance(length);
+                    self.drop_frame(error, length);
+                }
+                Step::NeedMore => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.encode_into(dst)?;
+        Ok(())
+    }
+}
+
+impl FileInfoCodec {
+    pub fn new() -> Self {
+        Self { remaining: 0 }
+    }
+
+    fn decode_header(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<FileInfo>> {
+        let Some((file_info, header_len)) = FileInfo::decode_prefix(src)? else {
+            return Ok(None);
+        };
+        src.advance(header_len);
+        self.remaining = file_info.f_len;
+
+        Ok(Some(file_info))
+    }
+}
+
+impl Default for FileInfoCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for FileInfoCodec {
+    type Item = FileFrame;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<FileFrame>, Self::Error> {
+        if self.remaining == 0 {
+            return Ok(self.decode_header(src)?.map(FileFrame::Header));
+        }
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        let length = src.len().min(CHUNK_SIZE).min(self.remaining as usize);
+        self.remaining -= length as u64;
+        Ok(Some(FileFrame::Chunk(src.split_to(length).freeze())))
+    }
+}
+
+impl Encoder<FileFrame> for FileInfoCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: FileFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item {
+            FileFrame::Header(file_info) => dst.extend_from_slice(&file_info.encode()),
+            FileFrame::Chunk(bytes) => dst.extend_from_slice(&bytes),
+        }
+        Ok(())
+    }
+}
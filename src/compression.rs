@@ -0,0 +1,84 @@
+/**
+ * Compression applied to file transfers. Each algorithm is offered as a handshake
+ * capability and the sender picks the best one both sides support.
+ */
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None = 0,
+    Gzip = 1,
+    Zstd = 2,
+}
+
+impl Compression {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Gzip),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    // Also the capability name sent in the handshake
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    // Appended to the filename when an upload is stored compressed
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+
+    // Stops after limit bytes so a bad upload cannot fill the disk
+    pub fn decompress_file(&self, src: &Path, dst: &Path, limit: u64) -> io::Result<u64> {
+        let mut decoder = self.decoder(BufReader::new(File::open(src)?))?.take(limit.saturating_add(1));
+        let mut out_file = File::create(dst)?;
+        let written = io::copy(&mut decoder, &mut out_file)?;
+        if written > limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("decompresses to more than {} bytes", limit)));
+        }
+        out_file.sync_all()?;
+        Ok(written)
+    }
+
+    pub fn decompress(&self, data: &[u8], limit: u64) -> io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        self.decoder(data)?.take(limit.saturating_add(1)).read_to_end(&mut decompressed)?;
+        if decompressed.len() as u64 > limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("decompresses to more than {} bytes", limit)));
+        }
+        Ok(decompressed)
+    }
+
+    fn decoder<'a, R: Read + 'a>(&self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        })
+    }
+}
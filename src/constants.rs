@@ -1,4 +1,24 @@
 // The version of the message format.
-pub const PROTOCOL_VERSION: u8 = 1;
-// The size of the message metadata in bytes.
-pub const METADATA_SIZE: usize = 3;
\ No newline at end of file
+pub const PROTOCOL_VERSION: u8 = 6;
+// The oldest message format still accepted.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+// The original message format, spoken by peers that never send a handshake.
+pub const LEGACY_VERSION: u8 = 1;
+// Optional features this build offers during the handshake.
+pub const CAPABILITIES: &[&str] = &["replies", "file_ack", "resume", "zstd", "gzip", "batch", "cancel", "live", "commands"];
+// The size of the message metadata in bytes: version + u16 length.
+pub const METADATA_SIZE: usize = 3;
+// The size of the version 2 message metadata in bytes: version + u32 length.
+pub const METADATA_SIZE_V2: usize = 5;
+// The size of the version 3 message metadata in bytes: magic + version + u32 length.
+pub const METADATA_SIZE_V3: usize = 7;
+// The size of the version 4 message metadata in bytes: magic + version + frame type + u32 length.
+pub const METADATA_SIZE_V4: usize = 8;
+// The size of the version 6 message metadata in bytes: the version 4 metadata followed by its CRC32C.
+pub const METADATA_SIZE_V6: usize = 12;
+// The size of the CRC32C trailer on version 5 and later frames, which otherwise match version 4.
+pub const CHECKSUM_SIZE: usize = 4;
+// Marks the start of every version 3 and later frame so a reader can find the next frame after a corrupt one.
+pub const FRAME_MAGIC: [u8; 2] = [0xB7, 0x1E];
+// Frames larger than this are rejected unless the reader is configured otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
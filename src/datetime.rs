@@ -1,3 +1,4 @@
+#[derive(Debug, Clone, PartialEq)]
 pub struct DateTime {
     pub year: String,
     pub month: String,
@@ -24,16 +25,38 @@ impl DateTime{
         let joined = self.to_string();
         joined.into_bytes()
     }
-    pub fn decode(bytes: Vec<u8>) -> DateTime {
-        let datetime = String::from_utf8(bytes).unwrap();
+    // None when the bytes are not a datetime written by encode()
+    pub fn decode(bytes: Vec<u8>) -> Option<DateTime> {
+        let datetime = String::from_utf8(bytes).ok()?;
         let times: Vec<&str> = datetime.split(" ").collect();
-        
+        if times.len() != 4 {
+            return None;
+        }
+
         let year = times[0].to_string();
         let month = times[1].to_string();
         let day = times[2].to_string();
         let time = times[3].to_string();
+        if !DateTime::is_number(&year, 4, 0..=9999)
+            || !DateTime::is_number(&month, 2, 1..=12)
+            || !DateTime::is_number(&day, 2, 1..=31) {
+            return None;
+        }
+        // HH:MM:SS
+        let hms: Vec<&str> = time.split(":").collect();
+        if hms.len() != 3
+            || !DateTime::is_number(hms[0], 2, 0..=23)
+            || !DateTime::is_number(hms[1], 2, 0..=59)
+            || !DateTime::is_number(hms[2], 2, 0..=60) {
+            return None;
+        }
         let dt: DateTime = DateTime{year, month, day, time};
-        dt
+        Some(dt)
+    }
+    fn is_number(value: &str, digits: usize, range: std::ops::RangeInclusive<u32>) -> bool {
+        value.len() == digits
+            && value.bytes().all(|b| b.is_ascii_digit())
+            && value.parse().is_ok_and(|v| range.contains(&v))
     }
     pub fn to_string(&self) -> String {
         let joined = (&self.year).to_string() + " " + &self.month + " " + &self.day + " " + &self.time;
@@ -0,0 +1,335 @@
+use std::fmt;
+
+// The checksum carried by a frame does not match its contents
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChecksumMismatch {
+    pub expected: u32,
+    pub actual: u32,
+}
+
+// Problems with a single frame on the wire
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    // The version byte is not one this build understands
+    UnsupportedVersion(u8),
+    // The frame starts like a version 3 frame but the marker is wrong
+    BadMagic,
+    // The frame announces more content than the reader accepts
+    TooLarge { length: usize, max: usize },
+    // The content does not fit the length field of the frame's version
+    ContentTooLong { length: usize, version: u8 },
+    // The frame type byte is not one this build understands
+    UnknownFrameType(u8),
+    // The frame type cannot be carried by frames of this version
+    UnsupportedFrameType { kind: u8, version: u8 },
+    // The frame was damaged in transit
+    Checksum(ChecksumMismatch),
+    // The header of a version 6 frame was damaged, so neither its length nor its end can be trusted
+    HeaderChecksum(ChecksumMismatch),
+    // The buffer ends before the frame does
+    Incomplete,
+    InvalidUtf8,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::UnsupportedVersion(version) => write!(f, "Unsupported protocol version {}", version),
+            FrameError::BadMagic => write!(f, "Frame marker is corrupt"),
+            FrameError::TooLarge { length, max } => write!(f, "Frame of {} bytes exceeds the maximum of {} bytes", length, max),
+            FrameError::ContentTooLong { length, version } => write!(f, "Content of {} bytes does not fit a version {} frame", length, version),
+            FrameError::UnknownFrameType(kind) => write!(f, "Unknown frame type {}", kind),
+            FrameError::UnsupportedFrameType { kind, version } => write!(f, "Frame type {} cannot be sent in a version {} frame", kind, version),
+            FrameError::Checksum(mismatch) => write!(f, "{}", mismatch),
+            FrameError::HeaderChecksum(mismatch) => write!(f, "Header {}", mismatch),
+            FrameError::Incomplete => write!(f, "Invalid message length"),
+            FrameError::InvalidUtf8 => write!(f, "Frame content is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame checksum {:08x} does not match contents ({:08x})", self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+
+// Problems with a file transfer header
+#[derive(Debug)]
+pub enum FileInfoError {
+    // The type byte is not one this build understands
+    UnsupportedType(u8),
+    EmptyFilename,
+    // The announced filename is longer than the receiver accepts
+    FilenameTooLong { length: usize, max: usize },
+    InvalidFilename,
+    // The announced datetime is longer than encode() ever writes
+    DateTimeTooLong { length: usize, max: usize },
+    // The datetime is not in the "YYYY MM DD HH:MM:SS" form written by DateTime::encode
+    InvalidDateTime(String),
+    // An extension is cut short or its value does not fit its tag
+    InvalidExtension(u8),
+    // The buffer ends before the header does
+    Incomplete,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for FileInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileInfoError::UnsupportedType(kind) => write!(f, "Unsupported file header type {}", kind),
+            FileInfoError::EmptyFilename => write!(f, "File header has an empty filename"),
+            FileInfoError::FilenameTooLong { length, max } => write!(f, "Filename of {} bytes exceeds the maximum of {} bytes", length, max),
+            FileInfoError::InvalidFilename => write!(f, "Filename is not valid UTF-8"),
+            FileInfoError::DateTimeTooLong { length, max } => write!(f, "Datetime of {} bytes exceeds the maximum of {} bytes", length, max),
+            FileInfoError::InvalidDateTime(datetime) => write!(f, "Invalid datetime {:?}", datetime),
+            FileInfoError::InvalidExtension(tag) => write!(f, "Malformed file header extension {}", tag),
+            FileInfoError::Incomplete => write!(f, "File header is incomplete"),
+            FileInfoError::Io(e) => write!(f, "Reading file header failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FileInfoError {}
+
+impl From<std::io::Error> for FileInfoError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => FileInfoError::Incomplete,
+            _ => FileInfoError::Io(e),
+        }
+    }
+}
+
+
+// The receiver refused a file, e.g. because it did not match its digest
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadRejected {
+    pub filename: String,
+    pub reason: String,
+    // Sending the same file again would be refused the same way, e.g. its name is not allowed
+    pub permanent: bool,
+}
+
+impl UploadRejected {
+    // Sent as the reason in a file ack, with "rejected " in front when permanent
+    pub fn encode(&self) -> String {
+        if self.permanent {
+            format!("rejected {}", self.reason)
+        } else {
+            self.reason.clone()
+        }
+    }
+
+    pub fn decode(filename: String, reason: &str) -> Self {
+        match reason.strip_prefix("rejected ") {
+            Some(reason) => UploadRejected { filename, reason: reason.to_string(), permanent: true },
+            None => UploadRejected { filename, reason: reason.to_string(), permanent: false },
+        }
+    }
+}
+
+impl fmt::Display for UploadRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server rejected {}: {}", self.filename, self.reason)
+    }
+}
+
+impl std::error::Error for UploadRejected {}
+
+// The sender gave up on a file part way through, see TransferOptions::with_cancel
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferCancelled {
+    pub filename: String,
+    pub sent: u64,
+    pub total: u64,
+}
+
+impl fmt::Display for TransferCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transfer of {} cancelled after {} of {} bytes", self.filename, self.sent, self.total)
+    }
+}
+
+impl std::error::Error for TransferCancelled {}
+
+// A size or disk space limit the central server refuses an upload under, before taking any of it
+#[derive(Debug, Clone, PartialEq)]
+pub enum UploadLimit {
+    FileSize { size: u64, max: u64 },
+    // Bytes the host already has stored for the day, on top of which the upload would go
+    HostQuota { host: String, used: u64, size: u64, max: u64 },
+    DiskSpace { needed: u64, available: u64 },
+    // The server is already receiving as many files as it takes at once
+    Busy { max: u64 },
+}
+
+impl UploadLimit {
+    // Sent as the reason in a file ack, e.g. "limit file_size 5000 1000"
+    pub fn encode(&self) -> String {
+        match self {
+            UploadLimit::FileSize { size, max } => format!("limit file_size {} {}", size, max),
+            UploadLimit::HostQuota { host, used, size, max } => format!("limit host_quota {} {} {} {}", host, used, size, max),
+            UploadLimit::DiskSpace { needed, available } => format!("limit disk_space {} {}", needed, available),
+            UploadLimit::Busy { max } => format!("limit busy {}", max),
+        }
+    }
+
+    pub fn decode(reason: &str) -> Option<Self> {
+        let parts: Vec<&str> = reason.split(' ').collect();
+        let number = |index: usize| parts.get(index)?.parse::<u64>().ok();
+        match parts[..] {
+            ["limit", "file_size", _, _] => Some(UploadLimit::FileSize { size: number(2)?, max: number(3)? }),
+            ["limit", "host_quota", host, _, _, _] => Some(UploadLimit::HostQuota {
+                host: host.to_string(),
+                used: number(3)?,
+                size: number(4)?,
+                max: number(5)?,
+            }),
+            ["limit", "disk_space", _, _] => Some(UploadLimit::DiskSpace { needed: number(2)?, available: number(3)? }),
+            ["limit", "busy", _] => Some(UploadLimit::Busy { max: number(2)? }),
+            _ => None,
+        }
+    }
+
+    // Whether sending the same file again soon could succeed, as space may be freed or other
+    // transfers finish. A host quota only resets the next day.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, UploadLimit::DiskSpace { .. } | UploadLimit::Busy { .. })
+    }
+}
+
+impl fmt::Display for UploadLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadLimit::FileSize { size, max } => write!(f, "File of {} bytes exceeds the maximum of {} bytes", size, max),
+            UploadLimit::HostQuota { host, used, size, max } => {
+                write!(f, "{} has stored {} bytes today, another {} bytes exceeds the quota of {} bytes", host, used, size, max)
+            }
+            UploadLimit::DiskSpace { needed, available } => write!(f, "Upload needs {} bytes but only {} bytes are free", needed, available),
+            UploadLimit::Busy { max } => write!(f, "Server is already receiving the most files it takes at once ({})", max),
+        }
+    }
+}
+
+// The central server refused a file because of one of its limits
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadLimitExceeded {
+    pub filename: String,
+    pub limit: UploadLimit,
+}
+
+impl fmt::Display for UploadLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server refused {}: {}", self.filename, self.limit)
+    }
+}
+
+impl std::error::Error for UploadLimitExceeded {}
+
+// Why a filename sent with an upload was refused
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilenameError {
+    Empty,
+    Nul,
+    // Starts at the filesystem root, e.g. "/etc/cron.d/x"
+    Absolute,
+    // Contains a ".." component
+    ParentDir,
+    // Contains a directory separator, so is more than one component
+    Separator,
+    // Starts with a dot, which would hide it from listings
+    Hidden,
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for FilenameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilenameError::Empty => write!(f, "Filename is empty"),
+            FilenameError::Nul => write!(f, "Filename contains a NUL byte"),
+            FilenameError::Absolute => write!(f, "Filename is an absolute path"),
+            FilenameError::ParentDir => write!(f, "Filename refers to a parent directory"),
+            FilenameError::Separator => write!(f, "Filename contains a directory separator"),
+            FilenameError::Hidden => write!(f, "Filename starts with a dot"),
+            FilenameError::InvalidCharacter(c) => write!(f, "Filename contains {:?}", c),
+        }
+    }
+}
+
+impl std::error::Error for FilenameError {}
+
+// A connection went quiet for longer than the server waits
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionTimedOut {
+    // What the server was waiting for, e.g. "file data"
+    pub waiting_for: &'static str,
+    pub after: std::time::Duration,
+}
+
+impl fmt::Display for ConnectionTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timed out after {:?} waiting for {}", self.after, self.waiting_for)
+    }
+}
+
+impl std::error::Error for ConnectionTimedOut {}
+
+// The peer closed the connection, or reset it, while an answer was expected
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionClosed;
+
+impl fmt::Display for ConnectionClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Connection closed by server")
+    }
+}
+
+impl std::error::Error for ConnectionClosed {}
+
+// A command the server did not answer in time, e.g. sent to a server that never replies
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplyTimedOut {
+    pub id: u32,
+    pub after: std::time::Duration,
+}
+
+impl fmt::Display for ReplyTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No reply to command {} after {:?}", self.id, self.after)
+    }
+}
+
+impl std::error::Error for ReplyTimedOut {}
+
+// Why the logging server refused a command, see auth::CommandVerifier
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthError {
+    // The server has a key but the command was sent without a signature
+    Unsigned,
+    Malformed,
+    BadSignature,
+    // Signed too long ago, or too far ahead of the server's clock
+    Expired { skew: u64 },
+    // A command with the same nonce was already accepted
+    Replayed,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Unsigned => write!(f, "Command is not signed"),
+            AuthError::Malformed => write!(f, "Command signature is malformed"),
+            AuthError::BadSignature => write!(f, "Command signature does not match"),
+            AuthError::Expired { skew } => write!(f, "Command was signed {} seconds away from the server's clock", skew),
+            AuthError::Replayed => write!(f, "Command was already received"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
@@ -1,38 +1,263 @@
 /**
  * Structure and implementation for file transmissions across servers
  */
-use crate::log_utils;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::compression::Compression;
 use crate::datetime;
+use crate::error::FileInfoError;
+
+// Type byte that starts every file transfer header
+pub const FILE_TYPE: u8 = 101;
+// Type byte of a header followed by an extension block
+pub const FILE_TYPE_EXT: u8 = 102;
+// Extension tags, each extension is tag (1 byte) + value length (u16) + value
+pub const TAG_SHA256: u8 = 1;
+pub const TAG_UPLOAD_ID: u8 = 2;
+// Algorithm (1 byte) + uncompressed length (u64)
+pub const TAG_COMPRESSION: u8 = 3;
+// Type byte + file length + filename length
+const FIXED_SIZE: usize = 1 + 8 + 2;
+// Longest filename most filesystems accept
+pub const MAX_FILENAME_LEN: usize = 255;
+// "YYYY MM DD HH:MM:SS" with room to spare
+const MAX_DATETIME_LEN: usize = 32;
+// Upload ids name the receiver's partial file, so they are kept short and plain
+pub const MAX_UPLOAD_ID_LEN: usize = 64;
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct FileInfo {
     pub _type: u8,
     pub f_len: u64,
     pub fn_len: u16,
     pub filename: String,
     pub dt_len: u32,
-    pub datetime: datetime::DateTime
+    pub datetime: datetime::DateTime,
+    // Digest of the whole file, checked by the receiver once it has arrived
+    pub sha256: Option<[u8; 32]>,
+    // Stays the same across attempts at one upload so the receiver can resume it
+    pub upload_id: Option<String>,
+    // How the f_len bytes that follow are compressed, and their length once decompressed
+    pub compression: Compression,
+    pub original_len: u64,
 }
 
 impl FileInfo {
     pub fn new(file_len: u64, filename: String, datetime: datetime::DateTime) -> Self {
         Self {
-            _type: 101,
+            _type: FILE_TYPE,
             f_len: file_len,
             fn_len: filename.len() as u16,
             filename: filename,
             dt_len: datetime.encode().len() as u32,// Figure out how to read datetime from receive
-            datetime: datetime
+            datetime: datetime,
+            sha256: None,
+            upload_id: None,
+            compression: Compression::None,
+            original_len: file_len,
         }
     }
-    pub fn encode(self) -> Vec<u8> {
+
+    // Headers with extensions use the newer type byte, which older receivers reject
+    pub fn with_sha256(mut self, digest: [u8; 32]) -> Self {
+        self._type = FILE_TYPE_EXT;
+        self.sha256 = Some(digest);
+        self
+    }
+
+    pub fn with_upload_id(mut self, upload_id: impl Into<String>) -> Self {
+        self._type = FILE_TYPE_EXT;
+        self.upload_id = Some(upload_id.into());
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression, original_len: u64) -> Self {
+        self._type = FILE_TYPE_EXT;
+        self.compression = compression;
+        self.original_len = original_len;
+        self
+    }
+    pub fn encode(&self) -> Vec<u8> {
         let mut buffer = Vec::new();
         buffer.extend(self._type.to_be_bytes());
         buffer.extend(self.f_len.to_be_bytes());
         buffer.extend(self.fn_len.to_be_bytes());
-        buffer.extend(self.filename.into_bytes());
+        buffer.extend(self.filename.as_bytes());
         buffer.extend(self.dt_len.to_be_bytes());
         buffer.extend(self.datetime.encode());
 
+        if self._type == FILE_TYPE_EXT {
+            let extensions = self.encode_extensions();
+            buffer.extend((extensions.len() as u16).to_be_bytes());
+            buffer.extend(extensions);
+        }
+
+        buffer
+    }
+
+    fn encode_extensions(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        if let Some(digest) = &self.sha256 {
+            buffer.push(TAG_SHA256);
+            buffer.extend((digest.len() as u16).to_be_bytes());
+            buffer.extend(digest);
+        }
+        if let Some(upload_id) = &self.upload_id {
+            buffer.push(TAG_UPLOAD_ID);
+            buffer.extend((upload_id.len() as u16).to_be_bytes());
+            buffer.extend(upload_id.as_bytes());
+        }
+        if self.compression != Compression::None {
+            buffer.push(TAG_COMPRESSION);
+            buffer.extend(9u16.to_be_bytes());
+            buffer.push(self.compression as u8);
+            buffer.extend(self.original_len.to_be_bytes());
+        }
         buffer
     }
-}
\ No newline at end of file
+
+    // Parses a header from the front of the buffer, returning it with the number of bytes it
+    // used, or None when the buffer ends before the header does
+    pub fn decode_prefix(buffer: &[u8]) -> Result<Option<(Self, usize)>, FileInfoError> {
+        let Some(header_len) = Self::header_len(buffer)? else {
+            return Ok(None);
+        };
+        let f_len = u64::from_be_bytes(buffer[1..9].try_into().unwrap());
+        let dt_start = FIXED_SIZE + Self::filename_len(buffer);
+
+        let filename = String::from_utf8(buffer[FIXED_SIZE..dt_start].to_vec())
+            .map_err(|_| FileInfoError::InvalidFilename)?;
+        let dt_end = dt_start + 4 + Self::datetime_len(buffer, dt_start);
+        let dt_bytes = buffer[dt_start + 4..dt_end].to_vec();
+        let datetime = match datetime::DateTime::decode(dt_bytes.clone()) {
+            Some(datetime) => datetime,
+            None => return Err(FileInfoError::InvalidDateTime(String::from_utf8_lossy(&dt_bytes).into_owned())),
+        };
+
+        let mut file_info = Self::new(f_len, filename, datetime);
+        if buffer[0] == FILE_TYPE_EXT {
+            file_info._type = FILE_TYPE_EXT;
+            file_info.decode_extensions(&buffer[dt_end + 2..header_len])?;
+        }
+
+        Ok(Some((file_info, header_len)))
+    }
+
+    // Unknown tags are skipped so newer senders can add extensions
+    fn decode_extensions(&mut self, mut extensions: &[u8]) -> Result<(), FileInfoError> {
+        while !extensions.is_empty() {
+            if extensions.len() < 3 {
+                return Err(FileInfoError::InvalidExtension(extensions[0]));
+            }
+            let tag = extensions[0];
+            let len = u16::from_be_bytes([extensions[1], extensions[2]]) as usize;
+            let Some(value) = extensions.get(3..3 + len) else {
+                return Err(FileInfoError::InvalidExtension(tag));
+            };
+            match tag {
+                TAG_SHA256 => {
+                    let digest = value.try_into().map_err(|_| FileInfoError::InvalidExtension(tag))?;
+                    self.sha256 = Some(digest);
+                }
+                TAG_UPLOAD_ID => {
+                    let valid = !value.is_empty()
+                        && value.len() <= MAX_UPLOAD_ID_LEN
+                        && value.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'-' || *b == b'_');
+                    if !valid {
+                        return Err(FileInfoError::InvalidExtension(tag));
+                    }
+                    self.upload_id = Some(String::from_utf8_lossy(value).into_owned());
+                }
+                TAG_COMPRESSION => {
+                    let compression = match value {
+                        [algorithm, _, _, _, _, _, _, _, _] => Compression::from_u8(*algorithm),
+                        _ => None,
+                    };
+                    let Some(compression) = compression else {
+                        return Err(FileInfoError::InvalidExtension(tag));
+                    };
+                    self.compression = compression;
+                    self.original_len = u64::from_be_bytes(value[1..9].try_into().unwrap());
+                }
+                _ => {}
+            }
+            extensions = &extensions[3 + len..];
+        }
+        Ok(())
+    }
+
+    pub fn decode(buffer: &[u8]) -> Result<Self, FileInfoError> {
+        match Self::decode_prefix(buffer)? {
+            Some((file_info, _)) => Ok(file_info),
+            None => Err(FileInfoError::Incomplete),
+        }
+    }
+
+    // Reads exactly one header from the reader, leaving the file bytes after it unread
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, FileInfoError> {
+        let mut buffer = Vec::new();
+        // Each length is validated by required_len() before reading what it announces
+        loop {
+            let required = Self::required_len(&buffer)?;
+            if required == buffer.len() {
+                return Self::decode(&buffer);
+            }
+            let known = buffer.len();
+            buffer.resize(required, 0);
+            reader.read_exact(&mut buffer[known..]).await?;
+        }
+    }
+
+    // Total size of the header at the front of the buffer, once enough of it is there to tell
+    fn header_len(buffer: &[u8]) -> Result<Option<usize>, FileInfoError> {
+        let required = Self::required_len(buffer)?;
+        if buffer.len() < required {
+            return Ok(None);
+        }
+        Ok(Some(required))
+    }
+
+    // How much of the buffer the header needs, as far as the fields present so far tell.
+    // Only final once the buffer is at least that long.
+    fn required_len(buffer: &[u8]) -> Result<usize, FileInfoError> {
+        if buffer.len() < FIXED_SIZE {
+            return Ok(FIXED_SIZE);
+        }
+        if buffer[0] != FILE_TYPE && buffer[0] != FILE_TYPE_EXT {
+            return Err(FileInfoError::UnsupportedType(buffer[0]));
+        }
+        let fn_len = Self::filename_len(buffer);
+        if fn_len == 0 {
+            return Err(FileInfoError::EmptyFilename);
+        }
+        if fn_len > MAX_FILENAME_LEN {
+            return Err(FileInfoError::FilenameTooLong { length: fn_len, max: MAX_FILENAME_LEN });
+        }
+        let dt_start = FIXED_SIZE + fn_len;
+        if buffer.len() < dt_start + 4 {
+            return Ok(dt_start + 4);
+        }
+        let dt_len = Self::datetime_len(buffer, dt_start);
+        if dt_len > MAX_DATETIME_LEN {
+            return Err(FileInfoError::DateTimeTooLong { length: dt_len, max: MAX_DATETIME_LEN });
+        }
+        let dt_end = dt_start + 4 + dt_len;
+        if buffer[0] == FILE_TYPE {
+            return Ok(dt_end);
+        }
+
+        // Extension block: total length (u16) + extensions
+        if buffer.len() < dt_end + 2 {
+            return Ok(dt_end + 2);
+        }
+        Ok(dt_end + 2 + u16::from_be_bytes([buffer[dt_end], buffer[dt_end + 1]]) as usize)
+    }
+
+    fn datetime_len(buffer: &[u8], dt_start: usize) -> usize {
+        u32::from_be_bytes(buffer[dt_start..dt_start + 4].try_into().unwrap()) as usize
+    }
+
+    fn filename_len(buffer: &[u8]) -> usize {
+        u16::from_be_bytes([buffer[9], buffer[10]]) as usize
+    }
+}
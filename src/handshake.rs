@@ -0,0 +1,104 @@
+/**
+ * Connection-opening handshake. Both sides send the range of protocol versions and the
+ * capabilities they support, and the connection continues with the highest common version.
+ * Hello frames always use the legacy framing so that any peer can read them.
+ */
+use crate::constants::{CAPABILITIES, LEGACY_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::message::{FrameType, Message};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub min_version: u8,
+    pub max_version: u8,
+    pub capabilities: Vec<String>,
+}
+
+// What both sides agreed on for the rest of the connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub version: u8,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    // The versions and capabilities supported by this build
+    pub fn local() -> Self {
+        Self {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    // The answer to a hello states the agreed version as a single-version range
+    pub fn from_session(session: &Session) -> Self {
+        Self {
+            min_version: session.version,
+            max_version: session.version,
+            capabilities: session.capabilities.clone(),
+        }
+    }
+
+    pub fn negotiate(&self, peer: &Hello) -> anyhow::Result<Session> {
+        let version = self.max_version.min(peer.max_version);
+        if version < self.min_version.max(peer.min_version) {
+            anyhow::bail!(
+                "No common protocol version: local {}..={}, peer {}..={}",
+                self.min_version, self.max_version, peer.min_version, peer.max_version
+            );
+        }
+        let capabilities = self
+            .capabilities
+            .iter()
+            .filter(|c| peer.capabilities.contains(c))
+            .cloned()
+            .collect();
+
+        Ok(Session { version, capabilities })
+    }
+
+    // Sent as "hello <min> <max> <capability,capability>"
+    pub fn to_message(&self) -> Message {
+        let content = format!(
+            "hello {} {} {}",
+            self.min_version,
+            self.max_version,
+            self.capabilities.join(",")
+        );
+        Message::new(content.trim_end()).with_version(LEGACY_VERSION)
+    }
+
+    // None when the message is not a hello, e.g. a command from a client that skips the handshake
+    // or a file chunk that happens to start with "hello"
+    pub fn from_message(message: &Message) -> Option<Self> {
+        if message.kind != FrameType::Command {
+            return None;
+        }
+        let mut parts = message.text().split_whitespace();
+        if parts.next() != Some("hello") {
+            return None;
+        }
+        let min_version = parts.next()?.parse().ok()?;
+        let max_version = parts.next()?.parse().ok()?;
+        let capabilities = parts
+            .next()
+            .map(|caps| caps.split(',').map(|c| c.to_string()).collect())
+            .unwrap_or_default();
+
+        Some(Self { min_version, max_version, capabilities })
+    }
+}
+
+impl Session {
+    // Assumed for peers that never send a handshake
+    pub fn legacy() -> Self {
+        Self {
+            version: LEGACY_VERSION,
+            capabilities: Vec::new(),
+        }
+    }
+
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
@@ -1,9 +1,23 @@
 pub mod log_utils;
 pub mod server;
 pub mod message_reader;
+pub mod codec;
 pub mod constants;
+pub mod error;
 pub mod message;
+pub mod handshake;
 pub mod client;
 pub mod file_info;
+pub mod compression;
+pub mod upload;
+pub mod progress;
+pub mod limits;
+pub mod spool;
+pub mod pull;
+pub mod live;
+pub mod remote;
+pub mod transport;
+pub mod auth;
 pub mod datetime;
-pub mod central_state;
\ No newline at end of file
+pub mod central_state;
+pub mod logger_state;
\ No newline at end of file
@@ -0,0 +1,105 @@
+/**
+ * Admission control for the storing server: how many connections it keeps open and files it
+ * receives at once, how often each address may connect, and how long a connection may go quiet
+ */
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    // Connections open at once, idle sessions included, any more are turned away
+    pub max_connections: usize,
+    // Files being received at once, any more are refused until one finishes
+    pub max_transfers: usize,
+    // Connections one address may open in a burst, refilled at connect_rate per second
+    pub connect_burst: f64,
+    pub connect_rate: f64,
+    // How long a session may wait between files, loggers send heartbeats well within this
+    pub idle_timeout: Duration,
+    // How long a transfer, or a new connection's first bytes, may stall
+    pub read_timeout: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_connections: 4096,
+            max_transfers: 64,
+            connect_burst: 10.0,
+            connect_rate: 1.0,
+            idle_timeout: Duration::from_secs(180),
+            read_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+// Why a connection was turned away
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refusal {
+    Busy,
+    RateLimited,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Clone)]
+pub struct Admission {
+    limits: ConnectionLimits,
+    permits: Arc<Semaphore>,
+    transfers: Arc<Semaphore>,
+    buckets: Arc<Mutex<HashMap<IpAddr, TokenBucket>>>,
+}
+
+impl Admission {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Admission {
+            permits: Arc::new(Semaphore::new(limits.max_connections)),
+            transfers: Arc::new(Semaphore::new(limits.max_transfers)),
+            limits,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // The permit is held for as long as the connection is served. The rate is checked first so
+    // that one address flooding the server is refused without taking permits from the others.
+    pub async fn admit(&self, ip: IpAddr) -> Result<OwnedSemaphorePermit, Refusal> {
+        if !self.take_token(ip).await {
+            return Err(Refusal::RateLimited);
+        }
+        self.permits.clone().try_acquire_owned().map_err(|_| Refusal::Busy)
+    }
+
+    // The permit is held from a file's header until it is finished, cancelled or closed, so a
+    // session waiting between files does not count
+    pub fn start_transfer(&self) -> Result<OwnedSemaphorePermit, Refusal> {
+        self.transfers.clone().try_acquire_owned().map_err(|_| Refusal::Busy)
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    async fn take_token(&self, ip: IpAddr) -> bool {
+        let (burst, rate) = (self.limits.connect_burst, self.limits.connect_rate);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().await;
+
+        // Full buckets are no different from new ones, so addresses that have gone quiet are forgotten
+        buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst);
+
+        let bucket = buckets.entry(ip).or_insert(TokenBucket { tokens: burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
@@ -0,0 +1,129 @@
+/**
+ * Live streaming: a logger in streaming mode sends every line written to its log file to the
+ * central server as it is written, which stores it per host and passes it on to anyone
+ * watching. Lines are sent best effort, the report file stays the record that is kept.
+ */
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::datetime::DateTime;
+use crate::log_utils;
+use crate::message::LiveLine;
+use crate::upload;
+
+// Lines waiting to be sent beyond this are dropped rather than holding up logging
+const QUEUE_LENGTH: usize = 1024;
+// Lines a slow watcher may fall behind by before it misses some
+const WATCH_BACKLOG: usize = 1024;
+
+// Where log files opened while streaming send their lines
+static SINK: Mutex<Option<mpsc::Sender<String>>> = Mutex::new(None);
+
+// Logger side: the lines written since streaming was enabled
+pub struct LiveStream {
+    pub host: String,
+    lines: mpsc::Receiver<String>,
+}
+
+impl LiveStream {
+    // Every log file opened after this also sends its lines here
+    pub fn enable(host: impl Into<String>) -> Self {
+        let (sender, lines) = mpsc::channel(QUEUE_LENGTH);
+        *SINK.lock().unwrap() = Some(sender);
+        LiveStream { host: host.into(), lines }
+    }
+
+    pub async fn next(&mut self) -> Option<LiveLine> {
+        let line = self.lines.recv().await?;
+        Some(LiveLine { host: self.host.clone(), line })
+    }
+}
+
+// Whether LiveStream::enable has been called
+pub fn is_streaming() -> bool {
+    SINK.lock().unwrap().is_some()
+}
+
+// A log file that also streams each complete line it is given, see log_utils::new_log_file
+pub struct TeeFile {
+    file: File,
+    sink: Option<mpsc::Sender<String>>,
+    // Records arrive in pieces, so a line is held until its newline
+    pending: Vec<u8>,
+}
+
+impl TeeFile {
+    pub fn new(file: File) -> Self {
+        TeeFile { file, sink: SINK.lock().unwrap().clone(), pending: Vec::new() }
+    }
+}
+
+impl Write for TeeFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write_all(buf)?;
+        let Some(sink) = &self.sink else {
+            return Ok(buf.len());
+        };
+        self.pending.extend_from_slice(buf);
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            // Full while the central server is unreachable, the line is still in the file
+            let _ = sink.try_send(String::from_utf8_lossy(&line[..end]).to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Central server side: stores lines as they arrive and passes them on to watchers
+#[derive(Clone)]
+pub struct LiveLogs {
+    lines: broadcast::Sender<LiveLine>,
+}
+
+impl LiveLogs {
+    pub fn new() -> Self {
+        LiveLogs { lines: broadcast::channel(WATCH_BACKLOG).0 }
+    }
+
+    // Appends the line to the host's live log for the day it arrived on, e.g.
+    // "Logs/2025/07/25/web-1.live", returning where it was stored
+    pub async fn record(&self, line: &LiveLine) -> anyhow::Result<PathBuf> {
+        let host = upload::sanitise_filename(&line.host)?;
+        // Would be counted as a report of another host, see upload::admit
+        if host.contains('|') {
+            anyhow::bail!("Invalid hostname {:?}", line.host);
+        }
+        let dir = PathBuf::from(log_utils::create_log_dir(DateTime::now()));
+        fs::create_dir_all(&dir).await?;
+        // Not ".log", which GET /logs lists as reports
+        let path = dir.join(format!("{}.live", host));
+        let mut file = OpenOptions::new().create(true).append(true).open(&path).await?;
+        file.write_all(format!("{}\n", line.line).as_bytes()).await?;
+        // Written in the background otherwise, possibly after watchers have seen the line
+        file.flush().await?;
+
+        // Fails only when nobody is watching
+        let _ = self.lines.send(line.clone());
+        Ok(path)
+    }
+
+    // Lines from every host, from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveLine> {
+        self.lines.subscribe()
+    }
+}
+
+impl Default for LiveLogs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
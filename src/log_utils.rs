@@ -1,16 +1,23 @@
 use chrono;
 use log::LevelFilter;
 use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::OnceLock;
 use std::fs::create_dir_all;
 use std::fs::read_dir;
 use std::fs::remove_dir_all;
 use std::{io::Read, process::Command};
 
 use crate::datetime::DateTime;
+use crate::live::{self, TeeFile};
 
 const LOG_FOLDER: &str = "Logs/";
 const ROTATION_MONTHS: u32 = 2;
 
+// Where logs are kept instead of LOG_FOLDER, see set_log_folder
+static LOG_FOLDER_OVERRIDE: OnceLock<String> = OnceLock::new();
+
 // Logs whole lxd system, returns log file path
 pub fn log_system() -> (String, DateTime) {
     // Create and rotate log files
@@ -26,15 +33,34 @@ pub fn log_system() -> (String, DateTime) {
 
     // Log for each container
     for container in container_list {
-        let current_container = &container["NAME"];
-        log::info!("OUTPUT: {:?}", lxc_ps_aux(current_container));
-        log::info!("OUTPUT: {:?}", lxc_info(current_container));
-        log::info!("OUTPUT: {:?}", integrity_disk_space(current_container));
+        log_container_status(&container["NAME"]);
     }
     println!("Logged status");
     (fp, cur_time)
 }
 
+// Logs a single container, returns log file path
+pub fn log_container(container_name: &str) -> (String, DateTime) {
+    let (cur_time, fp) = new_log_file();
+    del_old_logs(&cur_time, ROTATION_MONTHS);
+
+    log_container_status(container_name);
+    println!("Logged status of {}", container_name);
+    (fp, cur_time)
+}
+
+fn log_container_status(container_name: &str) {
+    log::info!("OUTPUT: {:?}", lxc_ps_aux(container_name));
+    log::info!("OUTPUT: {:?}", lxc_info(container_name));
+    log::info!("OUTPUT: {:?}", integrity_disk_space(container_name));
+    // Only streamed loggers send the syslog tail, one record per line so that each is streamed as it is
+    if live::is_streaming() {
+        for line in log_file_health(container_name) {
+            log::info!("SYSLOG {}: {}", container_name, line);
+        }
+    }
+}
+
 // Create new log file in directory for the current time
 pub fn new_log_file() -> (DateTime, String) {
     let dt: DateTime = DateTime::now();
@@ -48,7 +74,9 @@ pub fn new_log_file() -> (DateTime, String) {
         Ok(_) => println!("Successfully created {}", file_path),
         Err(e) => eprint!("Failed to create directory: {}", e),
     }
-    simple_logging::log_to_file(file_path.clone(), LevelFilter::Info).unwrap();
+    // Also streams each record to the central server when live streaming is enabled
+    let file = File::create(&file_path).unwrap();
+    simple_logging::log_to(TeeFile::new(file), LevelFilter::Info);
 
     (dt, file_path)
 }
@@ -65,9 +93,14 @@ pub fn rotate_logs() {
 }
 
 fn del_dir(dir: &str, time_cutoff: u32) {
-    let paths = read_dir(dir).unwrap();
+    // Nothing to rotate before the first log is stored
+    let Ok(paths) = read_dir(dir) else {
+        return;
+    };
     for path in paths {
-        let entry = path.unwrap();
+        let Ok(entry) = path else {
+            continue;
+        };
         let cur_path = entry.path();
 
         // Only process directories, skip files
@@ -165,58 +198,63 @@ pub fn get_uptime() -> String {
 // Pass in log file received from central server
 // Reads the log file to extract running containers count and stores data in JSON
 pub fn update_server_data(log_file_path: String) {
+    // Read the log file to extract running containers info
+    if let Ok(content) = std::fs::read_to_string(&log_file_path) {
+        update_server_data_from(&log_file_path, &content);
+    } else {
+        log::error!("Failed to read log file: {}", log_file_path);
+    }
+}
+
+// Same as update_server_data for a log already in memory, e.g. one stored compressed
+pub fn update_server_data_from(log_file_path: &str, content: &str) {
     let file_name = &log_file_path.split("||").next().unwrap_or("");
     let server_name = file_name.split('/').last().unwrap_or("");
 
-    // Read the log file to extract running containers info
-    if let Ok(content) = std::fs::read_to_string(&log_file_path) {
-        let mut running_containers = 0;
-        let mut total_containers = 0;
-
-        // Parse the log file for running containers info
-        for line in content.lines() {
-            if let Some(pos) = line.find("RUNNING CONTAINERS:") {
-                // Parse "(running, total)" format that appears after the marker
-                println!("Reached here, line: {}", line);
-                // Find the '(' that comes after the marker position (avoid earlier parentheses)
-                if let Some(paren_start_rel) = line[pos..].find('(') {
-                    let paren_start = pos + paren_start_rel;
-                    if let Some(paren_end_rel) = line[paren_start..].find(')') {
-                        let paren_end = paren_start + paren_end_rel;
-                        let tuple_str = &line[paren_start + 1..paren_end];
-                        let parts: Vec<&str> = tuple_str.split(',').collect();
-                        if parts.len() == 2 {
-                            running_containers = parts[0].trim().parse().unwrap_or(0);
-                            total_containers = parts[1].trim().parse().unwrap_or(0);
-                        }
+    let mut running_containers = 0;
+    let mut total_containers = 0;
+
+    // Parse the log file for running containers info
+    for line in content.lines() {
+        if let Some(pos) = line.find("RUNNING CONTAINERS:") {
+            // Parse "(running, total)" format that appears after the marker
+            println!("Reached here, line: {}", line);
+            // Find the '(' that comes after the marker position (avoid earlier parentheses)
+            if let Some(paren_start_rel) = line[pos..].find('(') {
+                let paren_start = pos + paren_start_rel;
+                if let Some(paren_end_rel) = line[paren_start..].find(')') {
+                    let paren_end = paren_start + paren_end_rel;
+                    let tuple_str = &line[paren_start + 1..paren_end];
+                    let parts: Vec<&str> = tuple_str.split(',').collect();
+                    if parts.len() == 2 {
+                        running_containers = parts[0].trim().parse().unwrap_or(0);
+                        total_containers = parts[1].trim().parse().unwrap_or(0);
                     }
                 }
-                break; // Found the line, no need to continue
             }
+            break; // Found the line, no need to continue
         }
-        // Parse uptime info
-        let mut uptime = String::new();
-        for line in content.lines() {
-            if let Some(pos) = line.find("SYSTEM UPTIME:") {
-                uptime = line[pos + "SYSTEM UPTIME:".len()..].trim().to_string().replace("\"", "");
-                break;
-            }
+    }
+    // Parse uptime info
+    let mut uptime = String::new();
+    for line in content.lines() {
+        if let Some(pos) = line.find("SYSTEM UPTIME:") {
+            uptime = line[pos + "SYSTEM UPTIME:".len()..].trim().to_string().replace("\"", "");
+            break;
         }
-
-        // Store to JSON file
-        store_server_data_to_json(server_name, running_containers, total_containers, uptime);
-    } else {
-        log::error!("Failed to read log file: {}", log_file_path);
     }
+
+    // Store to JSON file
+    store_server_data_to_json(server_name, running_containers, total_containers, uptime);
 }
 
 // Helper function to store server data to JSON file
 fn store_server_data_to_json(server_name: &str, running: usize, total: usize, uptime: String) {
-    let json_file = "Logs/servers.json";
+    let json_file = servers_file();
     let mut data = serde_json::json!({});
 
     // Read existing JSON if it exists
-    if let Ok(content) = std::fs::read_to_string(json_file) {
+    if let Ok(content) = std::fs::read_to_string(&json_file) {
         data = serde_json::from_str(&content).unwrap_or(serde_json::json!({}));
     }
 
@@ -230,15 +268,50 @@ fn store_server_data_to_json(server_name: &str, running: usize, total: usize, up
 
     // Write to file
     if let Ok(json_str) = serde_json::to_string_pretty(&data) {
-        let _ = std::fs::write(json_file, json_str);
+        let _ = std::fs::write(&json_file, json_str);
         log::info!("Updated server data for {} in {}", server_name, json_file);
     } else {
         log::error!("Failed to serialize server data for {}", server_name);
     }
 }
 
-fn get_log_folder() -> String {
-    LOG_FOLDER.to_string()
+pub fn get_log_folder() -> String {
+    LOG_FOLDER_OVERRIDE.get().cloned().unwrap_or_else(|| LOG_FOLDER.to_string())
+}
+
+// Keeps logs, uploads and the spool under dir rather than Logs/ in the working directory. Set
+// once at startup, before any path is handed out.
+pub fn set_log_folder(dir: &Path) -> anyhow::Result<()> {
+    let folder = format!("{}/", dir.to_string_lossy().trim_end_matches('/'));
+    LOG_FOLDER_OVERRIDE
+        .set(folder)
+        .map_err(|_| anyhow::anyhow!("Log folder is already set to {}", get_log_folder()))
+}
+
+// What is known about each server from its latest report, served at GET /servers
+pub fn servers_file() -> String {
+    get_log_folder() + "servers.json"
+}
+
+// Unfinished uploads waiting to be resumed, skipped by rotation as it is not numeric
+pub fn partial_upload_dir() -> String {
+    get_log_folder() + ".partial"
+}
+
+// Bytes free for unprivileged use on the filesystem holding path
+pub fn available_space(path: &std::path::Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+// Reports waiting to be sent to the central server, skipped by rotation like the partial uploads
+pub fn spool_dir() -> String {
+    get_log_folder() + ".spool"
 }
 
 // read and extract output of ps -- aux
@@ -397,8 +470,8 @@ pub fn integrity_disk_space(container_name: &str) -> Vec<HashMap<String, String>
     data
 }
 
-// Log File Health
-pub fn log_file_health(container_name: &str) {
+// Log File Health, the last lines of the container's syslog
+pub fn log_file_health(container_name: &str) -> Vec<String> {
     let output = lxc_command(&[
         "exec",
         container_name,
@@ -408,7 +481,7 @@ pub fn log_file_health(container_name: &str) {
         "100",
         "/var/log/syslog",
     ]);
-    // Understand the data output
+    output.lines().map(|line| line.to_string()).collect()
 }
 
 // LXD Storage Pool Status
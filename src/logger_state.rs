@@ -0,0 +1,28 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+// Time between reports when no interval has been set
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60 * 4);
+
+#[derive(Clone)]
+pub struct LoggerState {
+    // Shared state between the logging daemon and the command listener
+    pub running: Arc<Mutex<bool>>,
+    pub interval: Arc<Mutex<Duration>>,
+}
+
+impl LoggerState {
+    pub fn new() -> Self {
+        Self {
+            running: Arc::new(Mutex::new(true)),
+            interval: Arc::new(Mutex::new(DEFAULT_INTERVAL)),
+        }
+    }
+}
+
+impl Default for LoggerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
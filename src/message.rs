@@ -1,51 +1,514 @@
-use crate::constants::{METADATA_SIZE, PROTOCOL_VERSION};
+use std::time::Duration;
 
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::constants::{
+    CHECKSUM_SIZE, FRAME_MAGIC, METADATA_SIZE, METADATA_SIZE_V2, METADATA_SIZE_V3, METADATA_SIZE_V4, METADATA_SIZE_V6, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use crate::error::{ChecksumMismatch, FrameError};
+
+// Version 1 frames: version (1 byte) + content length (u16) + content
+// Version 2 frames: version (1 byte) + content length (u32) + content
+// Version 3 frames: magic (2 bytes) + version (1 byte) + content length (u32) + content
+// Version 4 frames: magic (2 bytes) + version (1 byte) + frame type (1 byte) + content length (u32) + content
+// Version 5 frames: a version 4 frame followed by the CRC32C of everything before it (u32)
+// Version 6 frames: a version 5 frame with the CRC32C of the version 4 header (u32) between the
+// header and the content, so a damaged length is caught before the content is waited for
 #[derive(Debug, Clone)]
 pub struct Message {
     pub version: u8,
-    pub length: u16,
-    pub content: String,
+    pub kind: FrameType,
+    pub length: u32,
+    // UTF-8 text for commands, replies and heartbeats, raw bytes for file frames.
+    // Decoded messages share the receive buffer rather than copying out of it.
+    pub content: Bytes,
+}
+
+// What a frame carries. Frames older than version 4 have no type byte and are
+// always commands or replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Command = 1,
+    Reply = 2,
+    // An encoded FileInfo announcing the file chunks that follow
+    FileHeader = 3,
+    FileChunk = 4,
+    // Keeps an idle connection open, answered with a heartbeat
+    Heartbeat = 5,
+    // Sent by the receiver once a whole file has been checked and stored
+    FileAck = 6,
+    // Answers a resumable file header with how many bytes the receiver already holds
+    FileResume = 7,
+    // Closes a batch of files, answered with the result of every file in it
+    BatchEnd = 8,
+    // One line of a logger's log, sent as it is written and not answered
+    LogLine = 9,
+}
+
+// The fixed part of a frame, read before the content has arrived
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    pub version: u8,
+    // Raw frame type byte, checked once the whole frame has arrived
+    pub kind: u8,
+    pub header_size: usize,
+    pub length: usize,
+    // Checksum bytes after the content
+    pub trailer_size: usize,
+}
+
+impl FrameHeader {
+    // Size of the whole frame, header and trailer included
+    pub fn frame_size(&self) -> usize {
+        self.header_size + self.length + self.trailer_size
+    }
+}
+
+// Commands understood by the logging server
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    // Close the connection
+    Exit,
+    // Log the whole system, or a single container
+    Collect { container: Option<String> },
+    // Collect, then send the report back over the same connection ahead of the reply
+    Fetch { container: Option<String> },
+    // List containers
+    List,
+    // Stop the logging daemon from producing reports
+    Pause,
+    // Let the logging daemon produce reports again
+    Resume,
+    // Print whether the daemon is running and its interval
+    Status,
+    // Change the time between reports
+    SetInterval(Duration),
+}
+
+// A command tagged with the id its reply will carry
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub id: u32,
+    pub command: Command,
+}
+
+// Sent back by the logging server for every request it handles
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub id: u32,
+    pub status: ReplyStatus,
+}
+
+// A line a logger wrote to its log, streamed to the central server as it happens
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveLine {
+    pub host: String,
+    pub line: String,
+}
+
+// What happened to one file of a batch
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileResult {
+    pub filename: String,
+    pub status: ReplyStatus,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplyStatus {
+    Ok,
+    Error(String),
+    Payload(String),
+}
+
+impl FrameType {
+    pub fn from_u8(kind: u8) -> Result<Self, FrameError> {
+        match kind {
+            1 => Ok(FrameType::Command),
+            2 => Ok(FrameType::Reply),
+            3 => Ok(FrameType::FileHeader),
+            4 => Ok(FrameType::FileChunk),
+            5 => Ok(FrameType::Heartbeat),
+            6 => Ok(FrameType::FileAck),
+            7 => Ok(FrameType::FileResume),
+            8 => Ok(FrameType::BatchEnd),
+            9 => Ok(FrameType::LogLine),
+            kind => Err(FrameError::UnknownFrameType(kind)),
+        }
+    }
+
+    // Text frames are checked to be UTF-8 when decoded
+    pub fn is_text(&self) -> bool {
+        matches!(self, FrameType::Command | FrameType::Reply | FrameType::Heartbeat | FrameType::FileAck | FrameType::FileResume | FrameType::BatchEnd | FrameType::LogLine)
+    }
 }
 
 impl Message {
     pub fn new(content: impl Into<String>) -> Self {
+        Self::with_kind(FrameType::Command, content.into())
+    }
+
+    pub fn with_kind(kind: FrameType, content: impl Into<Bytes>) -> Self {
         let content = content.into();
-        let length = content.len() as u16;
+        // encode() refuses content that does not fit, so saturating here never reaches the wire
+        let length = u32::try_from(content.len()).unwrap_or(u32::MAX);
         let version = PROTOCOL_VERSION;
 
         Self {
             version,
+            kind,
             length,
             content,
         }
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        buffer.push(self.version);
-        buffer.extend(&self.length.to_be_bytes());
-        buffer.extend(self.content.as_bytes());
+    pub fn heartbeat() -> Self {
+        Self::with_kind(FrameType::Heartbeat, Bytes::new())
+    }
+
+    // Content of a text frame, empty for file frames
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(&self.content).unwrap_or("")
+    }
+
+    // Frames the message for a peer that agreed on an older version
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    // Requests are sent as "<id> <command>", e.g. "7 collect web-1"
+    pub fn from_request(request: &Request) -> Self {
+        Self::new(format!("{} {}", request.id, request.command.encode()))
+    }
+
+    pub fn to_request(&self) -> anyhow::Result<Request> {
+        let id = self.request_id();
+        let command = match self.text().split_once(' ') {
+            Some((first, rest)) if first.parse::<u32>().is_ok() => Command::decode(rest)?,
+            // Older clients send the bare command without an id
+            _ => Command::decode(self.text())?,
+        };
+
+        Ok(Request { id, command })
+    }
+
+    // Id of a request, 0 when the sender did not provide one
+    pub fn request_id(&self) -> u32 {
+        self.text()
+            .split_whitespace()
+            .next()
+            .and_then(|first| first.parse().ok())
+            .unwrap_or(0)
+    }
+
+    // Replies are sent as "<id> ok", "<id> error <reason>" or "<id> payload <data>"
+    pub fn from_reply(reply: &Reply) -> Self {
+        Self::with_kind(FrameType::Reply, format!("{} {}", reply.id, reply.status.encode()))
+    }
+
+    pub fn to_reply(&self) -> anyhow::Result<Reply> {
+        let (id, status) = self.text().split_once(' ').unwrap_or((self.text(), ""));
+        let id = id
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Reply has no id: {:?}", self.text()))?;
+        let status = ReplyStatus::decode(status)
+            .map_err(|_| anyhow::anyhow!("Reply not recognised: {:?}", self.text()))?;
+
+        Ok(Reply { id, status })
+    }
+
+    // File acks carry the status alone, e.g. "ok" or "error checksum mismatch"
+    pub fn file_ack(status: &ReplyStatus) -> Self {
+        Self::with_kind(FrameType::FileAck, status.encode())
+    }
+
+    pub fn to_file_ack(&self) -> anyhow::Result<ReplyStatus> {
+        ReplyStatus::decode(self.text())
+    }
+
+    // Sent empty by the client, answered with a JSON list such as
+    // [{"filename": "web-1||09:08:12.log", "status": "ok"}]
+    pub fn batch_end(results: &[FileResult]) -> Self {
+        let results: Vec<serde_json::Value> = results
+            .iter()
+            .map(|result| serde_json::json!({ "filename": result.filename, "status": result.status.encode() }))
+            .collect();
+        Self::with_kind(FrameType::BatchEnd, serde_json::Value::from(results).to_string())
+    }
+
+    pub fn to_batch_results(&self) -> anyhow::Result<Vec<FileResult>> {
+        let value: serde_json::Value = serde_json::from_str(self.text())?;
+        let Some(results) = value.as_array() else {
+            anyhow::bail!("Batch results not recognised: {:?}", self.text());
+        };
+        results
+            .iter()
+            .map(|result| {
+                match (result["filename"].as_str(), result["status"].as_str()) {
+                    (Some(filename), Some(status)) => Ok(FileResult {
+                        filename: filename.to_string(),
+                        status: ReplyStatus::decode(status)?,
+                    }),
+                    _ => anyhow::bail!("Batch result not recognised: {}", result),
+                }
+            })
+            .collect()
+    }
+
+    // The offset is sent as decimal text, e.g. "65536"
+    pub fn file_resume(offset: u64) -> Self {
+        Self::with_kind(FrameType::FileResume, offset.to_string())
+    }
+
+    pub fn to_file_resume(&self) -> anyhow::Result<u64> {
+        self.text()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Resume offset not recognised: {:?}", self.text()))
+    }
+
+    // Log lines are sent as "<host> <line>", hostnames have no spaces
+    pub fn log_line(line: &LiveLine) -> Self {
+        Self::with_kind(FrameType::LogLine, format!("{} {}", line.host, line.line))
+    }
+
+    pub fn to_log_line(&self) -> anyhow::Result<LiveLine> {
+        match self.text().split_once(' ') {
+            Some((host, line)) if !host.is_empty() => Ok(LiveLine { host: host.to_string(), line: line.to_string() }),
+            _ => anyhow::bail!("Log line not recognised: {:?}", self.text()),
+        }
+    }
+
+    // Fails when the content does not fit the length field of the message's version,
+    // or the version has no way to carry the frame type
+    pub fn check_length(&self) -> Result<(), FrameError> {
+        let max = match self.version {
+            1 => u16::MAX as usize,
+            2..=6 => u32::MAX as usize,
+            version => return Err(FrameError::UnsupportedVersion(version)),
+        };
+        if self.version < 4 && !matches!(self.kind, FrameType::Command | FrameType::Reply) {
+            return Err(FrameError::UnsupportedFrameType { kind: self.kind as u8, version: self.version });
+        }
+        if self.content.len() > max {
+            return Err(FrameError::ContentTooLong { length: self.content.len(), version: self.version });
+        }
+        Ok(())
+    }
 
-        buffer
+    pub fn encode(&self) -> Result<Vec<u8>, FrameError> {
+        let mut buffer = BytesMut::with_capacity(METADATA_SIZE_V6 + self.content.len() + CHECKSUM_SIZE);
+        self.encode_into(&mut buffer)?;
+        Ok(buffer.to_vec())
     }
 
-    pub fn decode(buffer: &[u8]) -> anyhow::Result<Self> {
-        if buffer.len() < METADATA_SIZE {
-            return Err(anyhow::anyhow!("Invalid message length"));
+    // Appends the frame to the buffer, nothing is written if the message cannot be framed
+    pub fn encode_into(&self, buffer: &mut BytesMut) -> Result<(), FrameError> {
+        self.check_length()?;
+        let length = self.content.len();
+        let start = buffer.len();
+        buffer.reserve(METADATA_SIZE_V6 + length + CHECKSUM_SIZE);
+        match self.version {
+            1 => {
+                buffer.put_u8(self.version);
+                buffer.put_u16(length as u16);
+            }
+            2 => {
+                buffer.put_u8(self.version);
+                buffer.put_u32(length as u32);
+            }
+            3 => {
+                buffer.put_slice(&FRAME_MAGIC);
+                buffer.put_u8(self.version);
+                buffer.put_u32(length as u32);
+            }
+            _ => {
+                buffer.put_slice(&FRAME_MAGIC);
+                buffer.put_u8(self.version);
+                buffer.put_u8(self.kind as u8);
+                buffer.put_u32(length as u32);
+                if self.version >= 6 {
+                    let checksum = crc32c::crc32c(&buffer[start..]);
+                    buffer.put_u32(checksum);
+                }
+            }
         }
+        buffer.put_slice(&self.content);
+        if self.version >= 5 {
+            let checksum = crc32c::crc32c(&buffer[start..]);
+            buffer.put_u32(checksum);
+        }
+
+        Ok(())
+    }
 
-        let version = buffer[0];
-        if version != PROTOCOL_VERSION {
-            return Err(anyhow::anyhow!("Invalid protocol version"));
+    // Reads the frame header at the start of the buffer, or None when the
+    // header has not fully arrived yet
+    pub fn parse_header(buffer: &[u8]) -> Result<Option<FrameHeader>, FrameError> {
+        let Some(&first) = buffer.first() else {
+            return Ok(None);
+        };
+
+        // Version 3 onwards starts with the marker, older frames with the version
+        let has_magic = first == FRAME_MAGIC[0];
+        let version = if has_magic {
+            if buffer.len() < FRAME_MAGIC.len() + 1 {
+                return Ok(None);
+            }
+            if buffer[1] != FRAME_MAGIC[1] {
+                return Err(FrameError::BadMagic);
+            }
+            buffer[2]
+        } else {
+            first
+        };
+        let supported = (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) && (version >= 3) == has_magic;
+        if !supported {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let header_size = match version {
+            1 => METADATA_SIZE,
+            2 => METADATA_SIZE_V2,
+            3 => METADATA_SIZE_V3,
+            4 | 5 => METADATA_SIZE_V4,
+            _ => METADATA_SIZE_V6,
+        };
+        if buffer.len() < header_size {
+            return Ok(None);
+        }
+        // Checked before the length is used, so a damaged length is never waited on
+        if version >= 6 {
+            let field = &buffer[METADATA_SIZE_V4..header_size];
+            let expected = u32::from_be_bytes([field[0], field[1], field[2], field[3]]);
+            let actual = crc32c::crc32c(&buffer[..METADATA_SIZE_V4]);
+            if expected != actual {
+                return Err(FrameError::HeaderChecksum(ChecksumMismatch { expected, actual }));
+            }
         }
 
-        let length = u16::from_be_bytes([buffer[1], buffer[2]]);
-        let content = String::from_utf8(buffer[3..3 + length as usize].to_vec())?;
+        let (kind, length) = match version {
+            1 => (FrameType::Command as u8, u16::from_be_bytes([buffer[1], buffer[2]]) as usize),
+            2 => (FrameType::Command as u8, u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]) as usize),
+            3 => (FrameType::Command as u8, u32::from_be_bytes([buffer[3], buffer[4], buffer[5], buffer[6]]) as usize),
+            _ => (buffer[3], u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]) as usize),
+        };
+        let trailer_size = if version >= 5 { CHECKSUM_SIZE } else { 0 };
+        Ok(Some(FrameHeader { version, kind, header_size, length, trailer_size }))
+    }
+
+    pub fn decode(buffer: &[u8]) -> Result<Self, FrameError> {
+        Self::decode_frame(Bytes::copy_from_slice(buffer))
+    }
+
+    // Decodes a frame split off a receive buffer, the content points into the same memory
+    pub fn decode_frame(frame: Bytes) -> Result<Self, FrameError> {
+        let header = Self::parse_header(&frame)?.ok_or(FrameError::Incomplete)?;
+        if frame.len() < header.frame_size() {
+            return Err(FrameError::Incomplete);
+        }
+        let content_end = header.header_size + header.length;
+        if header.trailer_size > 0 {
+            let trailer = &frame[content_end..header.frame_size()];
+            let expected = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+            let actual = crc32c::crc32c(&frame[..content_end]);
+            if expected != actual {
+                return Err(FrameError::Checksum(ChecksumMismatch { expected, actual }));
+            }
+        }
+        let kind = FrameType::from_u8(header.kind)?;
+        let content = frame.slice(header.header_size..content_end);
+        if kind.is_text() && std::str::from_utf8(&content).is_err() {
+            return Err(FrameError::InvalidUtf8);
+        }
 
         Ok(Self {
-            version,
-            length,
+            version: header.version,
+            kind,
+            length: header.length as u32,
             content,
         })
     }
-}
\ No newline at end of file
+}
+
+impl ReplyStatus {
+    pub fn encode(&self) -> String {
+        match self {
+            ReplyStatus::Ok => "ok".to_string(),
+            ReplyStatus::Error(reason) => format!("error {}", reason),
+            ReplyStatus::Payload(data) => format!("payload {}", data),
+        }
+    }
+
+    pub fn decode(text: &str) -> anyhow::Result<Self> {
+        let (kind, rest) = text.split_once(' ').unwrap_or((text, ""));
+        match kind {
+            "ok" => Ok(ReplyStatus::Ok),
+            "error" => Ok(ReplyStatus::Error(rest.to_string())),
+            "payload" => Ok(ReplyStatus::Payload(rest.to_string())),
+            _ => anyhow::bail!("Status not recognised: {:?}", text),
+        }
+    }
+}
+
+impl Command {
+    // Text form carried as the content of a Message, e.g. "collect web-1" or "set_interval 3600"
+    pub fn encode(&self) -> String {
+        match self {
+            Command::Exit => "exit".to_string(),
+            Command::Collect { container: None } => "collect".to_string(),
+            Command::Collect { container: Some(name) } => format!("collect {}", name),
+            Command::Fetch { container: None } => "fetch".to_string(),
+            Command::Fetch { container: Some(name) } => format!("fetch {}", name),
+            Command::List => "list".to_string(),
+            Command::Pause => "pause".to_string(),
+            Command::Resume => "resume".to_string(),
+            Command::Status => "status".to_string(),
+            Command::SetInterval(interval) => format!("set_interval {}", interval.as_secs()),
+        }
+    }
+
+    // Name understood by logging servers from before replies, which match the whole text of a
+    // command and have no equivalent for the rest
+    pub fn legacy_name(&self) -> Option<&'static str> {
+        match self {
+            Command::Exit => Some("exit"),
+            Command::Collect { container: None } => Some("syslog"),
+            Command::List => Some("list"),
+            Command::Pause => Some("stop"),
+            Command::Resume => Some("start"),
+            _ => None,
+        }
+    }
+
+    pub fn decode(text: &str) -> anyhow::Result<Self> {
+        let mut parts = text.split_whitespace();
+        let name = parts.next().unwrap_or("");
+        let args: Vec<&str> = parts.collect();
+
+        let command = match (name, args.as_slice()) {
+            ("exit", []) => Command::Exit,
+            // "syslog" is the name used by older clients
+            ("collect" | "syslog", []) => Command::Collect { container: None },
+            ("collect", [container]) => Command::Collect { container: Some(container.to_string()) },
+            ("fetch", []) => Command::Fetch { container: None },
+            ("fetch", [container]) => Command::Fetch { container: Some(container.to_string()) },
+            ("list", []) => Command::List,
+            // "stop", "start" and "continue" are the names used by older clients
+            ("pause" | "stop", []) => Command::Pause,
+            ("resume" | "start" | "continue", []) => Command::Resume,
+            ("status", []) => Command::Status,
+            ("set_interval", [secs]) => {
+                let secs: u64 = secs
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid interval: {:?}", secs))?;
+                if secs == 0 {
+                    anyhow::bail!("Interval must be greater than zero");
+                }
+                Command::SetInterval(Duration::from_secs(secs))
+            }
+            _ => anyhow::bail!("Command not recognised: {:?}", text),
+        };
+
+        Ok(command)
+    }
+}
@@ -1,40 +1,80 @@
-use crate::{constants::METADATA_SIZE, message::Message};
+use bytes::{Buf, BytesMut};
+
+use crate::{constants::{DEFAULT_MAX_FRAME_SIZE, FRAME_MAGIC}, error::FrameError, message::Message};
 
 pub struct MessageReader {
-    pub buffer: Vec<u8>,
+    // Frames are split off the front of this buffer without copying the rest
+    pub buffer: BytesMut,
+    // Frames announcing more content than this are rejected before being buffered
+    pub max_frame_size: usize,
+    // Corrupt frames skipped so far, and the bytes thrown away with them
+    pub dropped_frames: u64,
+    pub dropped_bytes: u64,
+    // Why frames were dropped since the last call to take_errors()
+    errors: Vec<FrameError>,
+}
+
+// What to do with the front of a receive buffer
+pub(crate) enum Step {
+    // A complete frame of this many bytes is at the front
+    Frame(usize),
+    // This many bytes are corrupt and should be thrown away
+    Skip { length: usize, error: FrameError },
+    NeedMore,
 }
 
 impl MessageReader {
     pub fn new() -> Self {
-        Self { buffer: Vec::new() }
+        Self::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            max_frame_size,
+            dropped_frames: 0,
+            dropped_bytes: 0,
+            errors: Vec::new(),
+        }
     }
 
-    pub fn read(&mut self, data: &[u8]) -> anyhow::Result<Vec<Message>> {
+    // Returns every complete frame in the buffer. Corrupt frames are skipped and
+    // recorded, reading carries on from the next frame marker.
+    pub fn read(&mut self, data: &[u8]) -> Vec<Message> {
         self.buffer.extend_from_slice(data);
         let mut data = vec![];
-        while self.can_parse() {
-            let message = self.parse_first()?;
-            data.push(message);
+        loop {
+            match next_step(&self.buffer, self.max_frame_size) {
+                Step::Frame(message_length) => match self.parse_first(message_length) {
+                    Ok(message) => data.push(message),
+                    // The header was sound so the frame boundary is still known
+                    Err(e) => self.drop_frame(e, message_length),
+                },
+                Step::Skip { length, error } => {
+                    self.buffer.advance(length);
+                    self.drop_frame(error, length);
+                }
+                Step::NeedMore => break,
+            }
         }
 
-        Ok(data)
+        data
     }
 
-    fn can_parse(&self) -> bool {
-        if self.buffer.len() < METADATA_SIZE {
-            return false;
-        }
-        let length = u16::from_be_bytes([self.buffer[1], self.buffer[2]]);
-        self.buffer.len() >= METADATA_SIZE + length as usize
+    pub fn take_errors(&mut self) -> Vec<FrameError> {
+        std::mem::take(&mut self.errors)
     }
 
-    fn parse_first(&mut self) -> anyhow::Result<Message> {
-        let length = u16::from_be_bytes([self.buffer[1], self.buffer[2]]);
-        let message_length = METADATA_SIZE + length as usize;
-        let message = self.buffer[..message_length].to_vec();
-        self.buffer = self.buffer[message_length..].to_vec();
+    fn parse_first(&mut self, message_length: usize) -> Result<Message, FrameError> {
+        let message = self.buffer.split_to(message_length).freeze();
 
-        Message::decode(&message)
+        Message::decode_frame(message)
+    }
+
+    fn drop_frame(&mut self, error: FrameError, length: usize) {
+        self.dropped_frames += 1;
+        self.dropped_bytes += length as u64;
+        self.errors.push(error);
     }
 }
 
@@ -42,4 +82,29 @@ impl Default for MessageReader {
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
+
+pub(crate) fn next_step(buffer: &[u8], max_frame_size: usize) -> Step {
+    match Message::parse_header(buffer) {
+        Ok(Some(header)) if header.length > max_frame_size => Step::Skip {
+            length: resync_length(buffer),
+            error: FrameError::TooLarge { length: header.length, max: max_frame_size },
+        },
+        Ok(Some(header)) if buffer.len() >= header.frame_size() => Step::Frame(header.frame_size()),
+        Ok(_) => Step::NeedMore,
+        Err(error) => Step::Skip { length: resync_length(buffer), error },
+    }
+}
+
+// Number of bytes before the next frame marker
+fn resync_length(buffer: &[u8]) -> usize {
+    let next_magic = buffer[1..]
+        .windows(FRAME_MAGIC.len())
+        .position(|window| window == FRAME_MAGIC)
+        .map(|position| position + 1);
+    // Without a marker keep the last byte if it may be the first half of one
+    next_magic.unwrap_or_else(|| match buffer.last() {
+        Some(&last) if last == FRAME_MAGIC[0] && buffer.len() > 1 => buffer.len() - 1,
+        _ => buffer.len(),
+    })
+}
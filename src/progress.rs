@@ -0,0 +1,94 @@
+/**
+ * Following and aborting a file transfer from another task, e.g. to show a progress bar
+ * while Client::send_file_with runs
+ */
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::TransferCancelled;
+
+// Where a transfer has got to, sent after every chunk
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransferProgress {
+    pub filename: String,
+    // Bytes the receiver holds, counting any it kept from an earlier attempt
+    pub sent: u64,
+    // Bytes on the wire, which for a compressed file is its compressed size
+    pub total: u64,
+    // Bytes per second over this attempt
+    pub throughput: f64,
+    // None until there is a throughput to go on
+    pub eta: Option<Duration>,
+}
+
+impl TransferProgress {
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        self.sent as f64 / self.total as f64
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TransferOptions {
+    progress: Option<watch::Sender<TransferProgress>>,
+    cancel: CancellationToken,
+}
+
+impl TransferOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_progress(mut self, progress: watch::Sender<TransferProgress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    // Cancelling the token stops the transfer after the chunk being sent
+    pub fn with_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn cancel_token(&self) -> &CancellationToken {
+        &self.cancel
+    }
+}
+
+// Turns the bytes sent so far into progress updates
+pub(crate) struct ProgressTracker<'a> {
+    options: &'a TransferOptions,
+    filename: String,
+    total: u64,
+    // Bytes the receiver already held when this attempt started
+    offset: u64,
+    started: Instant,
+}
+
+impl<'a> ProgressTracker<'a> {
+    pub(crate) fn new(options: &'a TransferOptions, filename: &str, total: u64, offset: u64) -> Self {
+        let tracker = ProgressTracker { options, filename: filename.to_string(), total, offset, started: Instant::now() };
+        tracker.update(0);
+        tracker
+    }
+
+    pub(crate) fn cancelled(&self, streamed: u64) -> TransferCancelled {
+        TransferCancelled { filename: self.filename.clone(), sent: self.offset + streamed, total: self.total }
+    }
+
+    // streamed counts the bytes sent in this attempt
+    pub(crate) fn update(&self, streamed: u64) {
+        let Some(progress) = &self.options.progress else {
+            return;
+        };
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let throughput = if elapsed > 0.0 { streamed as f64 / elapsed } else { 0.0 };
+        let sent = self.offset + streamed;
+        let eta = (throughput > 0.0).then(|| Duration::from_secs_f64(self.total.saturating_sub(sent) as f64 / throughput));
+        // Kept even when nobody is watching yet
+        progress.send_replace(TransferProgress { filename: self.filename.clone(), sent, total: self.total, throughput, eta });
+    }
+}
@@ -0,0 +1,131 @@
+/**
+ * Pull mode: the central server connects to a logger's command port and asks for a report
+ * now, and the logger collects one and sends it back over the same connection ahead of
+ * its reply
+ */
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio_util::codec::Framed;
+
+use crate::auth::CommandKey;
+use crate::client::Client;
+use crate::codec::MessageCodec;
+use crate::file_info::FileInfo;
+use crate::handshake::Session;
+use crate::message::{Command, FrameType, ReplyStatus};
+use crate::server::Server;
+use crate::transport::{Connection, TlsConnector};
+use crate::upload::{IncomingFile, UploadConfig};
+
+// The port loggers listen for commands on
+pub const LOGGER_COMMAND_PORT: u16 = 8080;
+// Collection shells out to lxc for every container, so it is given a while
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// Logger side of a fetch. The report is sent once, without resuming or acks, as the central
+// server asks again if it does not arrive. Failing to collect or read the report is answered
+// in the reply, an Err means the connection itself failed.
+pub async fn send_collected(frames: &mut Framed<Connection, MessageCodec>, session: &Session, container: Option<String>) -> anyhow::Result<ReplyStatus> {
+    if session.version < 4 {
+        return Ok(ReplyStatus::Error(format!("Fetching a report needs protocol version 4, not {}", session.version)));
+    }
+    let (fp, datetime) = match Server::collect(container).await {
+        Ok(collected) => collected,
+        Err(e) => return Ok(ReplyStatus::Error(e.to_string())),
+    };
+
+    let once = Session {
+        version: session.version,
+        capabilities: session.capabilities.iter().filter(|c| *c != "resume" && *c != "file_ack").cloned().collect(),
+    };
+    let upload = match Client::prepare_upload(&once, fp.clone(), datetime).await {
+        Ok(upload) => upload,
+        Err(e) => return Ok(ReplyStatus::Error(e.to_string())),
+    };
+    let sent = upload.send_unacknowledged(frames, session.version).await?;
+    println!("Sent report {} ({} bytes) to the central server", fp, sent);
+    Ok(ReplyStatus::Payload(fp))
+}
+
+// Central server side: fetches a report from the logger at host and stores it as if the logger
+// had pushed it, returning where it was stored. Connects over TLS when given a connector, and
+// signs its commands when given the logger's key.
+pub async fn fetch_report(
+    host: &str,
+    port: u16,
+    container: Option<String>,
+    uploads: &UploadConfig,
+    tls: Option<&TlsConnector>,
+    key: Option<CommandKey>,
+) -> anyhow::Result<PathBuf> {
+    let mut client = Client::connect_with(host, port, tls).await?;
+    if let Some(key) = key {
+        client = client.with_key(key);
+    }
+    let session = client.handshake().await?;
+    if session.version < 4 {
+        anyhow::bail!("{} cannot send reports on request, it speaks protocol version {}", host, session.version);
+    }
+    let id = client.send_request(Command::Fetch { container }).await?;
+
+    // Kept out here so that an unfinished report is removed however the fetch ends
+    let mut incoming: Option<IncomingFile> = None;
+    let result = match tokio::time::timeout(FETCH_TIMEOUT, receive_report(&mut client, host, id, uploads, &mut incoming)).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!("{} did not send a report within {:?}", host, FETCH_TIMEOUT)),
+    };
+    if let Some(file) = incoming {
+        let _ = file.close().await;
+    }
+    if result.is_ok() {
+        let _ = client.send_command(Command::Exit).await;
+    }
+    result
+}
+
+// Only a report for host itself is stored, as reports are attributed to the host named before "||"
+async fn receive_report(client: &mut Client, host: &str, id: u32, uploads: &UploadConfig, incoming: &mut Option<IncomingFile>) -> anyhow::Result<PathBuf> {
+    let mut stored = None;
+    loop {
+        let message = client.recv().await?;
+        match message.kind {
+            FrameType::FileHeader => {
+                if incoming.is_some() || stored.is_some() {
+                    anyhow::bail!("logger sent more than one report");
+                }
+                let mut file_info = FileInfo::decode(&message.content)?;
+                let claimed = file_info.filename.trim().split_once("||").map_or("", |(claimed, _)| claimed);
+                if claimed != host {
+                    anyhow::bail!("{} sent a report for {:?}", host, claimed);
+                }
+                file_info.upload_id = None;
+                let file = IncomingFile::create(file_info, uploads).await?;
+                if file.is_complete() {
+                    stored = Some(file.finish().await?);
+                } else {
+                    *incoming = Some(file);
+                }
+            }
+            FrameType::FileChunk => {
+                let Some(file) = incoming.as_mut() else {
+                    anyhow::bail!("report data received before header");
+                };
+                file.write(&message.content).await?;
+                if let Some(file) = incoming.take_if(|file| file.is_complete()) {
+                    stored = Some(file.finish().await?);
+                }
+            }
+            FrameType::Reply => {
+                let reply = message.to_reply()?;
+                if reply.id != id {
+                    continue;
+                }
+                return match reply.status {
+                    ReplyStatus::Error(reason) => Err(anyhow::anyhow!("Logger could not send a report: {}", reason)),
+                    _ => stored.ok_or_else(|| anyhow::anyhow!("Logger replied before sending its report")),
+                };
+            }
+            kind => println!("Ignoring {:?} frame while fetching a report", kind),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+/**
+ * Commands the central server sends to loggers over the connection each keeps open to the
+ * storing server, so a logger behind a firewall that only lets it connect out can still be
+ * paused, resumed or asked for its status. Loggers that answer them offer the "commands"
+ * capability, and check signatures as their command port does.
+ */
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+use crate::auth::{CommandKey, CommandVerifier};
+use crate::client::{Client, DEFAULT_REPLY_TIMEOUT};
+use crate::error::ReplyTimedOut;
+use crate::logger_state::LoggerState;
+use crate::message::{Command, FrameType, Message, Reply, ReplyStatus, Request};
+use crate::server::Server;
+
+// Commands waiting for a logger's session to send them
+const QUEUE_LENGTH: usize = 16;
+
+// A command ready to go out on a logger's session, and where its reply goes
+pub(crate) struct QueuedCommand {
+    pub id: u32,
+    pub message: Message,
+    pub reply: oneshot::Sender<Reply>,
+}
+
+struct Connected {
+    // Version agreed with the logger, which the signature covers
+    version: u8,
+    commands: mpsc::Sender<QueuedCommand>,
+}
+
+// Loggers connected to the storing server that answer commands, by the host named in their
+// client certificate, or by the address they connected from when they presented none
+#[derive(Clone, Default)]
+pub struct ConnectedLoggers {
+    loggers: Arc<Mutex<HashMap<String, Connected>>>,
+    next_id: Arc<AtomicU32>,
+    // Commands are signed when set, for loggers started with --command-key
+    key: Option<CommandKey>,
+    reply_timeout: Option<Duration>,
+}
+
+impl ConnectedLoggers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_key(mut self, key: CommandKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn with_reply_timeout(mut self, reply_timeout: Duration) -> Self {
+        self.reply_timeout = Some(reply_timeout);
+        self
+    }
+
+    pub async fn hosts(&self) -> Vec<String> {
+        let loggers = self.loggers.lock().await;
+        let mut hosts: Vec<String> = loggers
+            .iter()
+            .filter(|(_, logger)| !logger.commands.is_closed())
+            .map(|(host, _)| host.clone())
+            .collect();
+        hosts.sort();
+        hosts
+    }
+
+    // Sends a command to the logger connected as host and waits for its reply, or returns
+    // ReplyTimedOut once the reply timeout has passed
+    pub async fn send(&self, host: &str, command: Command) -> anyhow::Result<Reply> {
+        let (version, commands) = match self.loggers.lock().await.get(host) {
+            Some(logger) => (logger.version, logger.commands.clone()),
+            None => anyhow::bail!("{} is not connected", host),
+        };
+        // 0 is the id of a request sent without one
+        let id = loop {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+            if id != 0 {
+                break id;
+            }
+        };
+        let mut message = Message::from_request(&Request { id, command });
+        if let Some(key) = &self.key {
+            message = Message::new(key.sign(version, FrameType::Command, message.text())?);
+        }
+        let (reply, answer) = oneshot::channel();
+        let queued = QueuedCommand { id, message: message.with_version(version), reply };
+        if commands.send(queued).await.is_err() {
+            anyhow::bail!("{} is not connected", host);
+        }
+
+        let after = self.reply_timeout.unwrap_or(DEFAULT_REPLY_TIMEOUT);
+        match tokio::time::timeout(after, answer).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => anyhow::bail!("{} disconnected before replying to command {}", host, id),
+            Err(_) => Err(ReplyTimedOut { id, after }.into()),
+        }
+    }
+
+    // Called by the session a logger offered "commands" on. Takes the place of an earlier
+    // session for the same host, e.g. one whose connection has not yet been noticed to be gone.
+    pub(crate) async fn register(&self, host: &str, version: u8) -> mpsc::Receiver<QueuedCommand> {
+        let (commands, queued) = mpsc::channel(QUEUE_LENGTH);
+        self.loggers.lock().await.insert(host.to_string(), Connected { version, commands });
+        queued
+    }
+
+    // Forgets loggers whose session has ended
+    pub(crate) async fn remove_closed(&self) {
+        self.loggers.lock().await.retain(|_, logger| !logger.commands.is_closed());
+    }
+}
+
+// Logger side: answers a command frame the central server sent over client, checking its
+// signature when verifier is set
+pub async fn answer(client: &mut Client, state: &LoggerState, verifier: Option<&CommandVerifier>, message: &Message) -> anyhow::Result<()> {
+    let from = format!("{}:{}", client.server_host, client.server_port);
+    let reply = match Server::read_request(verifier, message, &from).await {
+        // Would only close the connection to the central server, which reconnects
+        Ok(Request { id, command: Command::Exit }) => {
+            Reply { id, status: ReplyStatus::Error("Exit is only taken on the logger's command port".to_string()) }
+        }
+        Ok(request) => Reply { id: request.id, status: Server::handle_command(state, request.command).await },
+        Err(reply) => reply,
+    };
+    client.send_message(Message::from_reply(&reply)).await
+}
@@ -1,19 +1,38 @@
+use std::collections::HashMap;
 use std::env::current_dir;
-use std::sync::Arc;
-use std::path::{Path, PathBuf};
-use tokio::fs::{File, self};
-use tokio::io::{AsyncWriteExt, AsyncReadExt, BufReader};
-use tokio::sync::Mutex;
-use tokio::net::TcpStream;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::time::Duration;
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::{Framed, FramedRead};
 
-use crate::{log_utils, message_reader::MessageReader, datetime, central_state};
+use crate::{auth, log_utils, central_state, pull};
+use crate::auth::{CommandKey, CommandVerifier};
+use crate::codec::{FileFrame, FileInfoCodec, MessageCodec};
+use crate::datetime::DateTime;
+use crate::error::{ConnectionTimedOut, UploadLimit, UploadLimitExceeded, UploadRejected};
+use crate::file_info::{FileInfo, FILE_TYPE};
+use crate::handshake::{Hello, Session};
+use crate::limits::{Admission, ConnectionLimits, Refusal};
+use crate::logger_state::LoggerState;
+use crate::transport::{Connection, TlsAcceptor};
+use crate::message::{Command, FileResult, FrameType, Message, Reply, ReplyStatus, Request};
+use crate::remote::QueuedCommand;
+use crate::upload::{self, IncomingFile, UploadConfig};
 
 
-const CHUNK_SIZE: usize = 100_000;
-
 pub struct Server {
     pub host: String,
     pub port: u16,
+    // Only used by the storing server
+    pub uploads: UploadConfig,
+    pub limits: ConnectionLimits,
+    // Connections are only accepted over TLS when set
+    pub tls: Option<TlsAcceptor>,
+    // Only used by the logging server, which refuses unsigned commands when set
+    pub auth: Option<CommandVerifier>,
 }
 
 impl Server {
@@ -21,72 +40,226 @@ impl Server {
         Server {
             host: host.into(),
             port,
+            uploads: UploadConfig::default(),
+            limits: ConnectionLimits::default(),
+            tls: None,
+            auth: None,
         }
     }
 
+    pub fn with_uploads(mut self, uploads: UploadConfig) -> Self {
+        self.uploads = uploads;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // See transport::acceptor
+    pub fn with_tls(mut self, tls: TlsAcceptor) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    // Commands must be signed with key, see auth::CommandKey::sign
+    pub fn with_auth(mut self, key: CommandKey) -> Self {
+        self.auth = Some(CommandVerifier::new(key));
+        self
+    }
+
     // Listens to and receives Message types
-    pub async fn run_logging_server(&self, running: Arc<Mutex<bool>>) -> anyhow::Result<()> {
-        let listener = tokio::net::TcpListener::bind(format!("{}:{}", self.host, self.port)).await?;
+    pub async fn run_logging_server(&self, state: LoggerState) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(format!("{}:{}", self.host, self.port)).await?;
         println!("TCP Server is running on {}:{}", self.host, self.port);
+        self.run_logging_server_on(listener, state).await
+    }
+
+    // As run_logging_server, on a listener that is already bound, e.g. to port 0
+    pub async fn run_logging_server_on(&self, listener: TcpListener, state: LoggerState) -> anyhow::Result<()> {
         loop {
-            let (mut socket, addr) = listener.accept().await?;
+            let (socket, addr) = listener.accept().await?;
             println!("Connection received from {}", addr);
 
-            let running_clone = Arc::clone(&running);
-            
-            tokio::task::spawn(async move {
-                let mut message_reader = MessageReader::new();
+            let state = state.clone();
+            let tls = self.tls.clone();
+            let verifier = self.auth.clone();
 
-                'handler: loop {
-                    let mut buffer = [0; 256];
-                    let bytes_read = socket.read(&mut buffer).await?;
+            tokio::task::spawn(async move {
+                let stream = match Connection::accept(socket, tls.as_ref()).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("TLS handshake with {} failed: {}", addr, e);
+                        return Ok(());
+                    }
+                };
+                let mut frames = Framed::new(stream, MessageCodec::new());
+                // Clients that skip the handshake speak the original protocol
+                let mut session = Session::legacy();
 
-                    let messages = message_reader.read(&buffer[..bytes_read])?;
+                // Read inputs sent by client, answering each one
+                while let Some(message) = frames.next().await {
+                    let message = message?;
+                    Server::report_dropped(frames.codec_mut(), addr);
 
-                    let mut running_guard = running_clone.lock().await;
-                    
-                    // Read inputs sent by client
-                    for message in messages {
-                        if message.content == "exit" {
-                            println!("Connection closed by client");
-                            break 'handler;
-                        }
-                        // Command to log the system
-                        else if message.content == "syslog" {
-                            log_utils::log_system();
+                    if let Some(hello) = Hello::from_message(&message) {
+                        match Server::accept_hello(&mut frames, &hello, addr).await {
+                            Ok(agreed) => session = agreed,
+                            Err(_) => break,
                         }
-                        // lxc_list
-                        else if message.content == "list"{
-                            log_utils::lxc_list(); // <-- Does not save any logs atm
-                        }
-                        // start
-                        else if message.content == "start" || message.content == "continue"{
-                            *running_guard = true;
+                        continue;
+                    }
+                    match message.kind {
+                        FrameType::Command => {}
+                        FrameType::Heartbeat => {
+                            frames.send(Message::heartbeat().with_version(session.version)).await?;
+                            continue;
                         }
-                        // stop / pause
-                        else if message.content == "stop" || message.content == "pause"{
-                            *running_guard = false;
+                        kind => {
+                            println!("Ignoring {:?} frame from {}", kind, addr);
+                            continue;
                         }
-                        else {
-                            println!("Command not recognised: {:?}", message);
+                    }
+
+                    let (id, status, exit) = match Server::read_request(verifier.as_ref(), &message, addr).await {
+                        Ok(Request { id, command: Command::Exit }) => (id, ReplyStatus::Ok, true),
+                        Ok(Request { id, command: Command::Fetch { container } }) => {
+                            (id, pull::send_collected(&mut frames, &session, container).await?, false)
                         }
+                        Ok(request) => (request.id, Server::handle_command(&state, request.command).await, false),
+                        Err(reply) => (reply.id, reply.status, false),
+                    };
+                    let reply = Reply { id, status };
+                    let mut answer = Message::from_reply(&reply).with_version(session.version);
+                    // e.g. a payload too large for a version 1 frame
+                    if let Err(e) = answer.check_length() {
+                        let reply = Reply { id: reply.id, status: ReplyStatus::Error(e.to_string()) };
+                        answer = Message::from_reply(&reply).with_version(session.version);
+                    }
+                    frames.send(answer).await?;
+
+                    if exit {
+                        break;
                     }
                 }
+                println!("Connection closed by client");
                 Ok::<(), anyhow::Error>(())
             });
         }
     }
 
+    // Writes a report of the whole system, or a single container, returning its path
+    pub async fn collect(container: Option<String>) -> anyhow::Result<(String, DateTime)> {
+        // Collection shells out to lxc and panics on failure, so keep it off the runtime
+        tokio::task::spawn_blocking(move || match container {
+            Some(name) => log_utils::log_container(&name),
+            None => log_utils::log_system(),
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Collection failed: {}", e))
+    }
+
+    // Acts on a single command received by the logging server
+    pub async fn handle_command(state: &LoggerState, command: Command) -> ReplyStatus {
+        match command {
+            Command::Exit => ReplyStatus::Ok,
+            Command::Collect { container } => match Server::collect(container).await {
+                Ok((fp, _)) => ReplyStatus::Payload(fp),
+                Err(e) => ReplyStatus::Error(e.to_string()),
+            },
+            // Needs the connection the command arrived on, see run_logging_server
+            Command::Fetch { .. } => ReplyStatus::Error("Reports can only be fetched over the logger's command port".to_string()),
+            Command::List => {
+                match tokio::task::spawn_blocking(log_utils::lxc_list).await {
+                    Ok(containers) => ReplyStatus::Payload(serde_json::json!(containers).to_string()),
+                    Err(e) => ReplyStatus::Error(format!("Listing containers failed: {}", e)),
+                }
+            }
+            Command::Pause => {
+                *state.running.lock().await = false;
+                ReplyStatus::Ok
+            }
+            Command::Resume => {
+                *state.running.lock().await = true;
+                ReplyStatus::Ok
+            }
+            Command::Status => {
+                let running = *state.running.lock().await;
+                let interval = *state.interval.lock().await;
+                ReplyStatus::Payload(serde_json::json!({
+                    "running": running,
+                    "interval_secs": interval.as_secs(),
+                }).to_string())
+            }
+            Command::SetInterval(interval) => {
+                *state.interval.lock().await = interval;
+                ReplyStatus::Ok
+            }
+        }
+    }
+
+    // The request a command frame carries once its signature checks out, or the reply refusing
+    // it. Used wherever a logger takes commands, see also remote::answer.
+    pub async fn read_request(verifier: Option<&CommandVerifier>, message: &Message, from: impl Display) -> Result<Request, Reply> {
+        let message = match verifier {
+            Some(verifier) => match verifier.verify(message.version, message.kind, message.text()).await {
+                Ok(request) => Message::new(request),
+                Err(e) => {
+                    println!("Rejected command from {}: {}", from, e);
+                    let id = Message::new(auth::request_text(message.text())).request_id();
+                    return Err(Reply { id, status: ReplyStatus::Error(e.to_string()) });
+                }
+            },
+            None => Message::new(message.text()),
+        };
+        message.to_request().map_err(|e| {
+            println!("{}", e);
+            Reply { id: message.request_id(), status: ReplyStatus::Error(e.to_string()) }
+        })
+    }
+
     // Listens to and receives files and metadata
     pub async fn run_storing_server(&self, state: central_state::CentralState) -> anyhow::Result<()>{
-        let listener = tokio::net::TcpListener::bind(format!("{}:{}", self.host, self.port)).await?;
+        upload::clean_stale_uploads().await?;
+        let listener = TcpListener::bind(format!("{}:{}", self.host, self.port)).await?;
         println!("TCP Server is running on {}:{}", self.host, self.port);
+        self.run_storing_server_on(listener, state).await
+    }
+
+    // As run_storing_server, on a listener that is already bound and without removing stale
+    // uploads first
+    pub async fn run_storing_server_on(&self, listener: TcpListener, state: central_state::CentralState) -> anyhow::Result<()> {
+        let admission = Admission::new(self.limits.clone());
         loop {
-            let (mut socket, addr) = listener.accept().await?;
+            let (socket, addr) = listener.accept().await?;
+            // Turned away by closing the socket before anything is read
+            let permit = match admission.admit(addr.ip()).await {
+                Ok(permit) => permit,
+                Err(refusal) => {
+                    println!("Refused connection from {}: {:?}", addr, refusal);
+                    let mut stats = state.connections.lock().await;
+                    match refusal {
+                        Refusal::Busy => stats.rejected_busy += 1,
+                        Refusal::RateLimited => stats.rejected_rate_limited += 1,
+                    }
+                    continue;
+                }
+            };
             println!("Connection received from {}", addr);
+            state.connections.lock().await.accepted += 1;
+            let uploads = self.uploads.clone();
+            let admission = admission.clone();
+            let tls = self.tls.clone();
+            let state = state.clone();
             tokio::task::spawn(async move {
-                if let Err(e) = Server::handle_receive(socket).await {
-                    eprintln!("Connection Error");
+                let result = Server::handle_receive(socket, uploads, admission, state.clone(), tls).await;
+                drop(permit);
+                if let Err(e) = result {
+                    if e.is::<ConnectionTimedOut>() {
+                        state.connections.lock().await.timed_out += 1;
+                    }
+                    eprintln!("Connection Error from {}: {}", addr, e);
                 } else{
                     println!("Finished transfer from {}", addr);
                 }
@@ -96,73 +269,354 @@ impl Server {
     }
     // Function called when processing a logfile sent from log server to central server
     // This runs on the central server
-    pub async fn handle_receive(mut stream: TcpStream) -> anyhow::Result<()> {
-        let mut reader = BufReader::new(&mut stream);
-
-        // Read type
-        let mut ty = [0u8; 1];
-        reader.read_exact(&mut ty).await?;
-        if ty[0] != 101u8 {
-            anyhow::bail!("unsupported type")
+    pub async fn handle_receive(stream: TcpStream, uploads: UploadConfig, admission: Admission, state: central_state::CentralState, tls: Option<TlsAcceptor>) -> anyhow::Result<()> {
+        let limits = admission.limits();
+        // Loggers old enough to send a bare file header do not speak TLS
+        if let Some(tls) = tls {
+            let stream = tokio::time::timeout(limits.read_timeout, Connection::accept(stream, Some(&tls)))
+                .await
+                .map_err(|_| ConnectionTimedOut { waiting_for: "the TLS handshake", after: limits.read_timeout })??;
+            let identity = stream.peer_identity()?;
+            if let Some(host) = &identity {
+                println!("{} presented a certificate for {}", stream.peer_addr()?, host);
+            }
+            return Server::handle_session(stream, &uploads, &admission, &state, identity.as_deref()).await;
         }
-
-        // Read length
-        let mut len_buf = [0u8; 8];
-        reader.read_exact(&mut len_buf).await?;
-        let total_len = u64::from_be_bytes(len_buf);
-
-        // Read filename length and name
-        let mut name_len_buf = [0u8; 2];
-        reader.read_exact(&mut name_len_buf).await?;
-        let name_len = u16::from_be_bytes(name_len_buf) as usize;
-        let mut name_buf = vec![0u8; name_len];
-        reader.read_exact(&mut name_buf).await?;
-        let filename = String::from_utf8(name_buf).unwrap();
-        
-        // --> Prepare output file path <--
-        // Read date time of the file
-        println!("filename = {}", filename);
-        let mut dt_len_buf = [0u8; 4];
-        reader.read_exact(&mut dt_len_buf).await?;
-        let dt_len = u32::from_be_bytes(dt_len_buf) as usize;
-        let mut dt_buf = vec![0u8; dt_len];
-        reader.read_exact(&mut dt_buf).await?;
-        let datetime = datetime::DateTime::decode(dt_buf);
-        println!("datetime = {}", datetime.to_string());
-
-        // Create Log path and Rotate Logs -- Need some refactoring here
-        log_utils::rotate_logs(); // Rotate logs
-        let dir_path = log_utils::create_log_dir(datetime); // Log Path
-
-        let mut out_path: PathBuf;
-        out_path = PathBuf::from(dir_path);
-        
-        if !out_path.exists() {
-            fs::create_dir_all(&out_path).await?;
+        // Older loggers send a bare file header, newer ones open with a frame
+        let mut first = [0u8; 1];
+        tokio::time::timeout(limits.read_timeout, stream.peek(&mut first))
+            .await
+            .map_err(|_| ConnectionTimedOut { waiting_for: "the first bytes", after: limits.read_timeout })??;
+        if first[0] == FILE_TYPE {
+            Server::handle_legacy_transfer(Connection::Plain(stream), &uploads, &admission, &state).await
+        } else {
+            Server::handle_session(Connection::Plain(stream), &uploads, &admission, &state, None).await
         }
-        out_path.push(filename.clone());
+    }
+
+    // One file per connection: a header followed by the raw file bytes
+    async fn handle_legacy_transfer(stream: Connection, uploads: &UploadConfig, admission: &Admission, state: &central_state::CentralState) -> anyhow::Result<()> {
+        let addr = stream.peer_addr()?;
+        let limits = admission.limits();
+        let mut frames = FramedRead::new(stream, FileInfoCodec::new());
 
-        // Create file
-        let mut out_file = File::create(&out_path).await?;
+        let mut file_info = match Server::next_within(&mut frames, limits.read_timeout, "a file header").await? {
+            Some(Ok(FileFrame::Header(file_info))) => file_info,
+            Some(Ok(FileFrame::Chunk(_))) => anyhow::bail!("file data received before header"),
+            Some(Err(e)) => return Err(e),
+            None => anyhow::bail!("connection closed before file header"),
+        };
+        // There is no way to tell the sender where to resume, so every byte is sent again
+        file_info.upload_id = None;
+        let mut incoming = match Server::start_upload(file_info, uploads, admission, state).await {
+            Ok(incoming) => incoming,
+            Err(e) => {
+                // Legacy senders cannot be told why, the connection is closed instead
+                println!("Rejected upload from {}: {}", addr, e);
+                return Err(e);
+            }
+        };
 
         // Read exactly total_len bytes and write to file
-        let mut remaining = total_len;
-        let mut buffer = vec![0u8; CHUNK_SIZE];
-        let mut written: u64 = 0;
-        while remaining > 0 {
-            let to_read = std::cmp::min(buffer.len() as u64, remaining) as usize;
-            reader.read_exact(&mut buffer[..to_read]).await?;
-            out_file.write_all(&buffer[..to_read]).await?;
-            remaining -= to_read as u64;
-            written += to_read as u64;
+        while !incoming.is_complete() {
+            let chunk = match Server::next_within(&mut frames, limits.read_timeout, "file data").await {
+                Ok(Some(Ok(FileFrame::Chunk(bytes)))) => incoming.write(&bytes).await,
+                Ok(Some(Ok(FileFrame::Header(_)))) => Err(anyhow::anyhow!("header received before file was complete")),
+                Ok(Some(Err(e))) | Err(e) => Err(e),
+                Ok(None) => break,
+            };
+            if let Err(e) = chunk {
+                let _ = incoming.close().await;
+                return Err(e);
+            }
+        }
+        if !incoming.is_complete() {
+            return incoming.close().await;
         }
-        out_file.flush().await?;
-        //println!("Received and saved {} bytes to {:?}", written, out_path);
-        
-        // Store server data to JSON file
-        println!("filename: {:?}, out_path: {:?}", filename, out_path);
-        log_utils::update_server_data(out_path.to_string_lossy().to_string());
-        
+        incoming.finish().await?;
+
         Ok(())
     }
-}
\ No newline at end of file
+
+    // A persistent connection carrying file transfers, heartbeats and commands as typed frames
+    // identity is the host named by the client's certificate, when it presented one
+    async fn handle_session(stream: Connection, uploads: &UploadConfig, admission: &Admission, state: &central_state::CentralState, identity: Option<&str>) -> anyhow::Result<()> {
+        let addr = stream.peer_addr()?;
+        let mut frames = Framed::new(stream, MessageCodec::new());
+        // The upload whose chunks are currently arriving, kept out here so that it is
+        // closed however the connection ends
+        let mut incoming: Option<IncomingFile> = None;
+
+        let result = Server::serve_session(&mut frames, uploads, admission, state, identity, addr, &mut incoming).await;
+        state.loggers.remove_closed().await;
+        match incoming {
+            Some(file) => result.and(file.close().await),
+            None => result,
+        }
+    }
+
+    async fn serve_session(
+        frames: &mut Framed<Connection, MessageCodec>,
+        uploads: &UploadConfig,
+        admission: &Admission,
+        state: &central_state::CentralState,
+        identity: Option<&str>,
+        addr: SocketAddr,
+        incoming: &mut Option<IncomingFile>,
+    ) -> anyhow::Result<()> {
+        let limits = admission.limits();
+        let mut session = Session::legacy();
+        // Files finished since the last end of batch
+        let mut batch: Vec<FileResult> = Vec::new();
+        // Bytes still to arrive for a file that was rejected by its header
+        let mut discarding: u64 = 0;
+        // Commands from the central server, for a logger that offered to answer them
+        let mut commands: Option<mpsc::Receiver<QueuedCommand>> = None;
+        // Where the replies to commands already sent go, by id
+        let mut waiting: HashMap<u32, oneshot::Sender<Reply>> = HashMap::new();
+
+        loop {
+            // A stalled transfer is given up on sooner than a quiet session
+            let (limit, waiting_for) = if incoming.is_some() || discarding > 0 {
+                (limits.read_timeout, "file data")
+            } else {
+                (limits.idle_timeout, "a frame")
+            };
+            let next = tokio::select! {
+                next = Server::next_within(frames, limit, waiting_for) => next?,
+                Some(command) = Server::next_command(&mut commands) => {
+                    frames.send(command.message).await?;
+                    waiting.insert(command.id, command.reply);
+                    continue;
+                }
+            };
+            let Some(message) = next else {
+                break;
+            };
+            let message = message?;
+            Server::report_dropped(frames.codec_mut(), addr);
+
+            if let Some(hello) = Hello::from_message(&message) {
+                session = Server::accept_hello(frames, &hello, addr).await?;
+                if session.version >= 4 && session.has("commands") {
+                    let host = identity.map_or_else(|| addr.ip().to_string(), |host| host.to_string());
+                    println!("{} takes commands as {}", addr, host);
+                    commands = Some(state.loggers.register(&host, session.version).await);
+                }
+                continue;
+            }
+
+            match message.kind {
+                FrameType::FileHeader => {
+                    if let Some(unfinished) = incoming {
+                        anyhow::bail!("new file started before {} was complete", unfinished.filename);
+                    }
+                    let mut file_info = FileInfo::decode(&message.content)?;
+                    // Only senders that negotiated resuming wait to be told where to start
+                    if !session.has("resume") {
+                        file_info.upload_id = None;
+                    }
+                    // Bytes that follow unless the sender waits to be told where to resume
+                    let unprompted = if file_info.upload_id.is_some() { 0 } else { file_info.f_len };
+                    // Reports are attributed to the host named before "||", see update_server_data
+                    let claimed = file_info.filename.trim().split_once("||").map_or("", |(host, _)| host);
+                    let created = match Server::check_host(identity, claimed) {
+                        Ok(()) => Server::start_upload(file_info, uploads, admission, state).await,
+                        Err(reason) => Err(UploadRejected { filename: file_info.filename, reason, permanent: true }.into()),
+                    };
+                    let file = match created {
+                        Ok(file) => file,
+                        Err(e) => {
+                            let (filename, reason) = match e.downcast::<UploadLimitExceeded>() {
+                                Ok(exceeded) => {
+                                    println!("Refused upload from {}: {}", addr, exceeded);
+                                    (exceeded.filename, exceeded.limit.encode())
+                                }
+                                Err(e) => {
+                                    let rejected = e.downcast::<UploadRejected>()?;
+                                    println!("Rejected upload from {}: {}", addr, rejected);
+                                    (rejected.filename.clone(), rejected.encode())
+                                }
+                            };
+                            let status = ReplyStatus::Error(reason);
+                            if session.has("file_ack") {
+                                frames.send(Message::file_ack(&status).with_version(session.version)).await?;
+                            }
+                            batch.push(FileResult { filename, status });
+                            discarding = unprompted;
+                            continue;
+                        }
+                    };
+                    if file.is_resumable() {
+                        frames.send(Message::file_resume(file.resumed_from).with_version(session.version)).await?;
+                    }
+                    if file.is_complete() {
+                        batch.push(Server::finish_upload(frames, &session, file, addr).await?);
+                    } else {
+                        *incoming = Some(file);
+                    }
+                }
+                FrameType::FileChunk if discarding > 0 => {
+                    discarding = discarding.saturating_sub(message.content.len() as u64);
+                }
+                FrameType::FileChunk => {
+                    let Some(mut file) = incoming.take() else {
+                        anyhow::bail!("file data received before header");
+                    };
+                    if let Err(e) = file.write(&message.content).await {
+                        // Left for handle_session to close
+                        *incoming = Some(file);
+                        return Err(e);
+                    }
+                    if file.is_complete() {
+                        batch.push(Server::finish_upload(frames, &session, file, addr).await?);
+                    } else {
+                        *incoming = Some(file);
+                    }
+                }
+                FrameType::Heartbeat => {
+                    frames.send(Message::heartbeat().with_version(session.version)).await?;
+                }
+                FrameType::Command => {
+                    // Loggers only close the connection, everything else is for the logging server
+                    let status = match message.to_request() {
+                        Ok(Request { command: Command::Exit, .. }) => break,
+                        Ok(request) => ReplyStatus::Error(format!("{:?} is not handled by the central server", request.command)),
+                        Err(e) => ReplyStatus::Error(e.to_string()),
+                    };
+                    let reply = Reply { id: message.request_id(), status };
+                    frames.send(Message::from_reply(&reply).with_version(session.version)).await?;
+                }
+                // Not answered, a line that cannot be stored is only missing from the live log
+                FrameType::LogLine => {
+                    let recorded = match message.to_log_line() {
+                        Ok(line) => match Server::check_host(identity, &line.host) {
+                            Ok(()) => state.live.record(&line).await,
+                            Err(reason) => Err(anyhow::anyhow!(reason)),
+                        },
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = recorded {
+                        println!("Dropped log line from {}: {}", addr, e);
+                    }
+                }
+                FrameType::BatchEnd => {
+                    if let Some(unfinished) = incoming {
+                        anyhow::bail!("batch ended before {} was complete", unfinished.filename);
+                    }
+                    let stored = batch.iter().filter(|result| result.status == ReplyStatus::Ok).count();
+                    println!("Batch of {} files from {}, {} stored", batch.len(), addr, stored);
+                    frames.send(Message::batch_end(&batch).with_version(session.version)).await?;
+                    batch.clear();
+                }
+                // The sender gave up on the file it was sending
+                FrameType::FileAck if incoming.is_some() || discarding > 0 => {
+                    discarding = 0;
+                    if let Some(file) = incoming.take() {
+                        println!("{} cancelled {} after {} of {} bytes", addr, file.filename, file.written, file.total_len);
+                        file.cancel().await?;
+                    }
+                }
+                FrameType::Reply => match message.to_reply() {
+                    Ok(reply) => match waiting.remove(&reply.id) {
+                        Some(sender) => {
+                            // The sender may have stopped waiting
+                            let _ = sender.send(reply);
+                        }
+                        None => println!("Ignoring reply {} from {}", reply.id, addr),
+                    },
+                    Err(e) => println!("Ignoring reply from {}: {}", addr, e),
+                },
+                FrameType::FileAck | FrameType::FileResume => {
+                    println!("Ignoring {:?} frame from {}", message.kind, addr);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Prepares the file a header announced once the server has room for another transfer
+    async fn start_upload(
+        file_info: FileInfo,
+        uploads: &UploadConfig,
+        admission: &Admission,
+        state: &central_state::CentralState,
+    ) -> anyhow::Result<IncomingFile> {
+        let Ok(permit) = admission.start_transfer() else {
+            state.connections.lock().await.rejected_transfers += 1;
+            let limit = UploadLimit::Busy { max: admission.limits().max_transfers as u64 };
+            return Err(UploadLimitExceeded { filename: file_info.filename, limit }.into());
+        };
+        Ok(IncomingFile::create(file_info, uploads).await?.with_transfer(permit))
+    }
+
+    // Stores a completed upload and, when the sender asked for it, tells it whether the file
+    // arrived intact. A rejected file does not end the connection.
+    async fn finish_upload(frames: &mut Framed<Connection, MessageCodec>, session: &Session, file: IncomingFile, addr: SocketAddr) -> anyhow::Result<FileResult> {
+        let filename = file.filename.clone();
+        let status = match file.finish().await {
+            Ok(out_path) => {
+                println!("Finished transfer of {:?} from {}", out_path, addr);
+                ReplyStatus::Ok
+            }
+            Err(e) => {
+                println!("Rejected upload from {}: {}", addr, e);
+                ReplyStatus::Error(e.to_string())
+            }
+        };
+        if session.has("file_ack") {
+            frames.send(Message::file_ack(&status).with_version(session.version)).await?;
+        }
+        Ok(FileResult { filename, status })
+    }
+
+    // A client with a certificate may only send reports and lines for the host it names
+    fn check_host(identity: Option<&str>, claimed: &str) -> Result<(), String> {
+        match identity {
+            Some(identity) if identity != claimed => {
+                Err(format!("claimed host {:?} does not match the client certificate for {}", claimed, identity))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Answers a hello with the agreed session, or an error reply when there is no common version
+    async fn accept_hello(frames: &mut Framed<Connection, MessageCodec>, hello: &Hello, addr: SocketAddr) -> anyhow::Result<Session> {
+        match Hello::local().negotiate(hello) {
+            Ok(session) => {
+                println!("Negotiated protocol version {} with {}", session.version, addr);
+                frames.send(Hello::from_session(&session).to_message()).await?;
+                Ok(session)
+            }
+            Err(e) => {
+                println!("{}", e);
+                let reply = Reply { id: 0, status: ReplyStatus::Error(e.to_string()) };
+                frames.send(Message::from_reply(&reply)).await?;
+                Err(e)
+            }
+        }
+    }
+
+    // Next item from a connection, or a ConnectionTimedOut once it has been quiet for limit
+    async fn next_within<S: Stream + Unpin>(frames: &mut S, limit: Duration, waiting_for: &'static str) -> anyhow::Result<Option<S::Item>> {
+        tokio::time::timeout(limit, frames.next())
+            .await
+            .map_err(|_| ConnectionTimedOut { waiting_for, after: limit }.into())
+    }
+
+    // Never finishes for a session that does not take commands
+    async fn next_command(commands: &mut Option<mpsc::Receiver<QueuedCommand>>) -> Option<QueuedCommand> {
+        match commands {
+            Some(commands) => commands.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    // A corrupt frame is skipped rather than closing the connection
+    fn report_dropped(codec: &mut MessageCodec, addr: SocketAddr) {
+        for e in codec.take_errors() {
+            println!("Dropped frame from {} ({} so far): {}", addr, codec.dropped_frames, e);
+        }
+    }
+}
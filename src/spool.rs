@@ -0,0 +1,180 @@
+/**
+ * Reports waiting on the logger to be sent to the central server. Each report is copied into
+ * its own directory, named by a sequence number and the report's datetime, and is only removed
+ * once the central server has confirmed storing it. The original stays in the log directory.
+ */
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs::{self, File};
+
+use crate::client::Client;
+use crate::datetime::DateTime;
+use crate::error::{UploadLimitExceeded, UploadRejected};
+
+#[derive(Debug, Clone)]
+pub struct Spool {
+    dir: PathBuf,
+    // The oldest reports are dropped once the spool holds more than either
+    max_bytes: u64,
+    max_reports: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpooledReport {
+    pub path: PathBuf,
+    pub datetime: DateTime,
+    pub len: u64,
+    sequence: u64,
+    entry_dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64, max_reports: usize) -> Self {
+        Spool { dir: dir.into(), max_bytes, max_reports }
+    }
+
+    // Copies a report in behind those already waiting. The copy is made under a hidden name and
+    // renamed into place, so a half-copied report is never sent.
+    pub async fn add(&self, report_path: &str, datetime: &DateTime) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir).await?;
+        let filename = Path::new(report_path)
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("report path has no filename: {:?}", report_path))?;
+        let sequence = self.reports().await?.last().map_or(1, |report| report.sequence + 1);
+        let entry_name = format!("{:010}_{}_{}_{}_{}", sequence, datetime.year, datetime.month, datetime.day, datetime.time);
+
+        let staging_dir = self.dir.join(format!(".{}", entry_name));
+        // Left behind if the logger stopped part way through an earlier copy
+        let _ = fs::remove_dir_all(&staging_dir).await;
+        fs::create_dir_all(&staging_dir).await?;
+        let staged = staging_dir.join(filename);
+        fs::copy(report_path, &staged).await?;
+        File::open(&staged).await?.sync_all().await?;
+        fs::rename(&staging_dir, self.dir.join(&entry_name)).await?;
+        println!("Spooled {} as report {}", report_path, sequence);
+
+        self.enforce_limits().await
+    }
+
+    // Waiting reports, oldest first
+    pub async fn reports(&self) -> anyhow::Result<Vec<SpooledReport>> {
+        let mut reports = Vec::new();
+        let Ok(mut entries) = fs::read_dir(&self.dir).await else {
+            return Ok(reports);
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                continue;
+            }
+            match Spool::read_entry(&entry.path(), &name).await? {
+                Some(report) => reports.push(report),
+                None => eprintln!("Ignoring unexpected spool entry {:?}", entry.path()),
+            }
+        }
+        reports.sort_by_key(|report| report.sequence);
+        Ok(reports)
+    }
+
+    // Sends a report, removing it once the central server has stored it or refused it for good.
+    // Anything else leaves it waiting for the next attempt.
+    pub async fn deliver(&self, client: &mut Client, report: &SpooledReport) -> anyhow::Result<()> {
+        match client.send_file(report.path.to_string_lossy().to_string(), report.datetime.clone()).await {
+            Ok(()) => println!("Report {:?} stored by central server", report.path),
+            // e.g. too large, so it would hold up every report behind it. The original is still
+            // in the log directory.
+            Err(e) if Spool::refused_for_good(&e) => {
+                eprintln!("Central server will not take report {:?}: {}", report.path, e);
+            }
+            Err(e) => return Err(e),
+        }
+        self.remove(report).await
+    }
+
+    fn refused_for_good(e: &anyhow::Error) -> bool {
+        if let Some(exceeded) = e.downcast_ref::<UploadLimitExceeded>() {
+            return !exceeded.limit.is_transient();
+        }
+        e.downcast_ref::<UploadRejected>().is_some_and(|rejected| rejected.permanent)
+    }
+
+    // A report that was already dropped to make room is not an error
+    pub async fn remove(&self, report: &SpooledReport) -> anyhow::Result<()> {
+        match fs::remove_dir_all(&report.entry_dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // "0000000012_2025_07_25_07:04:58" holding the one report file
+    async fn read_entry(entry_dir: &Path, name: &str) -> anyhow::Result<Option<SpooledReport>> {
+        let Some((sequence, datetime)) = name.split_once('_') else {
+            return Ok(None);
+        };
+        let (Ok(sequence), Some(datetime)) = (sequence.parse(), DateTime::decode(datetime.replace('_', " ").into_bytes())) else {
+            return Ok(None);
+        };
+        // Gone if it was dropped to make room while being listed
+        let Ok(mut files) = fs::read_dir(entry_dir).await else {
+            return Ok(None);
+        };
+        let Some(file) = files.next_entry().await? else {
+            return Ok(None);
+        };
+        Ok(Some(SpooledReport {
+            path: file.path(),
+            datetime,
+            len: file.metadata().await?.len(),
+            sequence,
+            entry_dir: entry_dir.to_path_buf(),
+        }))
+    }
+
+    // The newest report is always kept, however large
+    async fn enforce_limits(&self) -> anyhow::Result<()> {
+        let reports = self.reports().await?;
+        let mut total: u64 = reports.iter().map(|report| report.len).sum();
+        let mut count = reports.len();
+        for report in &reports[..reports.len().saturating_sub(1)] {
+            if total <= self.max_bytes && count <= self.max_reports {
+                break;
+            }
+            println!("Spool is full, dropping report {:?}", report.path);
+            self.remove(report).await?;
+            total -= report.len;
+            count -= 1;
+        }
+        Ok(())
+    }
+}
+
+// Delays between attempts, doubling up to a ceiling. Each is cut by a random amount of up to
+// half so that loggers that lost the central server together do not all return at once.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff { min, max, current: min }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        // Without randomness the full delay is used
+        let mut random = [0u8; 8];
+        let jitter = match getrandom::getrandom(&mut random) {
+            Ok(()) => u64::from_le_bytes(random) as f64 / u64::MAX as f64,
+            Err(_) => 0.0,
+        };
+        delay.mul_f64(1.0 - jitter / 2.0)
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
@@ -0,0 +1,166 @@
+/**
+ * The streams frames travel over: plain TCP, or TCP wrapped in TLS when the listener or
+ * client was given certificates. Certificates and keys are read from PEM files.
+ *
+ * A listener may also require clients to present a certificate signed by a given authority,
+ * whose subject common name is then taken as the client's hostname.
+ */
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::{client, server};
+
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+pub enum Connection {
+    Plain(TcpStream),
+    // Boxed as a TLS stream carries its buffers inline
+    TlsClient(Box<client::TlsStream<TcpStream>>),
+    TlsServer(Box<server::TlsStream<TcpStream>>),
+}
+
+impl Connection {
+    // Opens a connection, over TLS when a connector is given. The certificate must name host.
+    pub async fn connect(host: &str, port: u16, tls: Option<&TlsConnector>) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
+        let Some(tls) = tls else {
+            return Ok(Connection::Plain(stream));
+        };
+        let name = ServerName::try_from(host.to_string())?;
+        Ok(Connection::TlsClient(Box::new(tls.connect(name, stream).await?)))
+    }
+
+    // Completes the TLS handshake on an accepted connection when the listener has a certificate
+    pub async fn accept(stream: TcpStream, tls: Option<&TlsAcceptor>) -> anyhow::Result<Self> {
+        match tls {
+            Some(tls) => Ok(Connection::TlsServer(Box::new(tls.accept(stream).await?))),
+            None => Ok(Connection::Plain(stream)),
+        }
+    }
+
+    // The hostname in the certificate the peer presented, None when it presented none
+    pub fn peer_identity(&self) -> anyhow::Result<Option<String>> {
+        let Connection::TlsServer(stream) = self else {
+            return Ok(None);
+        };
+        let Some(cert) = stream.get_ref().1.peer_certificates().and_then(|certs| certs.first()) else {
+            return Ok(None);
+        };
+        let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref())
+            .map_err(|e| anyhow::anyhow!("Client certificate could not be read: {}", e))?;
+        let name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|name| name.as_str().ok())
+            .ok_or_else(|| anyhow::anyhow!("Client certificate {} has no common name", cert.subject()))?;
+        Ok(Some(name.to_string()))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Connection::Plain(stream) => stream.peer_addr(),
+            Connection::TlsClient(stream) => stream.get_ref().0.peer_addr(),
+            Connection::TlsServer(stream) => stream.get_ref().0.peer_addr(),
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::TlsClient(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::TlsServer(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::TlsClient(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::TlsServer(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::TlsClient(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::TlsServer(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::TlsClient(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::TlsServer(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// For a listener, from its certificate chain and private key
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> anyhow::Result<TlsAcceptor> {
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(read_certs(cert_path)?, read_key(key_path)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// For a listener that only accepts clients with a certificate signed by an authority in
+// client_ca_path, see Connection::peer_identity
+pub fn verifying_acceptor(cert_path: &Path, key_path: &Path, client_ca_path: &Path) -> anyhow::Result<TlsAcceptor> {
+    let verifier = WebPkiClientVerifier::builder(Arc::new(read_roots(client_ca_path)?)).build()?;
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(read_certs(cert_path)?, read_key(key_path)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// For a client, trusting only the certificates in ca_path, e.g. a self-signed server certificate
+pub fn connector(ca_path: &Path) -> anyhow::Result<TlsConnector> {
+    let config = ClientConfig::builder().with_root_certificates(read_roots(ca_path)?).with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+// connector that also presents a certificate naming this host, for servers that require one
+pub fn identified_connector(ca_path: &Path, cert_path: &Path, key_path: &Path) -> anyhow::Result<TlsConnector> {
+    let config = ClientConfig::builder()
+        .with_root_certificates(read_roots(ca_path)?)
+        .with_client_auth_cert(read_certs(cert_path)?, read_key(key_path)?)?;
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn read_roots(path: &Path) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+fn read_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| anyhow::anyhow!("Reading private key {:?} failed: {}", path, e))
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("Reading certificates {:?} failed: {}", path, e))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates in {:?}", path);
+    }
+    Ok(certs)
+}
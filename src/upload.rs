@@ -0,0 +1,374 @@
+/**
+ * Receiving side of a file transfer on the central server, shared by the one-file
+ * legacy transfer and file frames on a multiplexed connection
+ */
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use sha2::{Digest, Sha256};
+use tokio::fs::{File, OpenOptions, self};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::OwnedSemaphorePermit;
+
+use crate::compression::Compression;
+use crate::error::{FilenameError, UploadLimit, UploadLimitExceeded, UploadRejected};
+use crate::file_info::FileInfo;
+use crate::log_utils;
+
+// Partial uploads untouched for this long are removed at startup
+const STALE_PARTIAL_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// Largest upload accepted when no max_file_size is configured, so that announced sizes are
+// checked before anything is added to them
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024 * 1024;
+
+// How the central server treats uploads
+#[derive(Debug, Clone, Default)]
+pub struct UploadConfig {
+    // Keep compressed uploads as they arrived, e.g. "host||09:08:12.log.zst", instead of decompressing them
+    pub store_compressed: bool,
+    // Largest log accepted in one upload, compressed or not, MAX_FILE_SIZE when not set
+    pub max_file_size: Option<u64>,
+    // Most one host may store in a day's log directory
+    pub max_host_daily_size: Option<u64>,
+    // Space that accepting an upload must leave free on the filesystem holding the logs
+    pub min_free_space: u64,
+}
+
+pub struct IncomingFile {
+    pub filename: String,
+    pub out_path: PathBuf,
+    pub total_len: u64,
+    pub written: u64,
+    // Bytes already held from an earlier attempt at the same upload
+    pub resumed_from: u64,
+    // Where bytes are written as they arrive, a temporary file that is only moved or decompressed
+    // into out_path once verified. Resumable uploads use a partial file that outlives the connection.
+    write_path: PathBuf,
+    resumable: bool,
+    out_file: File,
+    compression: Compression,
+    original_len: u64,
+    store_compressed: bool,
+    // Digest announced by the sender, if any, and the one computed as bytes arrive
+    expected_sha256: Option<[u8; 32]>,
+    hasher: Sha256,
+    // Counts against the storing server's max_transfers until the upload ends, see Admission
+    transfer: Option<OwnedSemaphorePermit>,
+}
+
+impl IncomingFile {
+    // Prepares the output file for the upload a header announced
+    pub async fn create(file_info: FileInfo, config: &UploadConfig) -> anyhow::Result<Self> {
+        let filename = match sanitise_filename(&file_info.filename) {
+            Ok(filename) => filename,
+            Err(e) => return Err(UploadRejected { filename: file_info.filename, reason: e.to_string(), permanent: true }.into()),
+        };
+        let datetime = file_info.datetime.clone();
+
+        // --> Prepare output file path <--
+        println!("filename = {}", filename);
+        println!("datetime = {}", datetime.to_string());
+
+        // Create Log path and Rotate Logs -- Need some refactoring here
+        log_utils::rotate_logs(); // Rotate logs
+        let dir_path = log_utils::create_log_dir(datetime); // Log Path
+
+        let mut out_path = PathBuf::from(dir_path);
+        if !out_path.exists() {
+            fs::create_dir_all(&out_path).await?;
+        }
+        out_path.push(filename.clone());
+        IncomingFile::admit(&file_info, &filename, &out_path, config).await?;
+
+        let (write_path, out_file, written, hasher) = match &file_info.upload_id {
+            Some(upload_id) => IncomingFile::open_partial(upload_id, file_info.f_len).await?,
+            None => {
+                let write_path = IncomingFile::temp_path().await?;
+                // Create file
+                (write_path.clone(), File::create(&write_path).await?, 0, Sha256::new())
+            }
+        };
+        if written > 0 {
+            println!("Resuming {} from {} of {} bytes", filename, written, file_info.f_len);
+        }
+
+        Ok(Self {
+            filename,
+            out_path,
+            total_len: file_info.f_len,
+            written,
+            resumed_from: written,
+            write_path,
+            resumable: file_info.upload_id.is_some(),
+            out_file,
+            compression: file_info.compression,
+            original_len: file_info.original_len,
+            store_compressed: config.store_compressed,
+            expected_sha256: file_info.sha256,
+            hasher,
+            transfer: None,
+        })
+    }
+
+    // Holds the permit for as long as the upload is in progress
+    pub fn with_transfer(mut self, permit: OwnedSemaphorePermit) -> Self {
+        self.transfer = Some(permit);
+        self
+    }
+
+    // Refuses an upload that is over a size limit or would not fit on disk, before any of it is written
+    async fn admit(file_info: &FileInfo, filename: &str, out_path: &Path, config: &UploadConfig) -> anyhow::Result<()> {
+        let decompressing = file_info.compression != Compression::None && !config.store_compressed;
+        let stored_len = if decompressing { file_info.original_len } else { file_info.f_len };
+        let refuse = |limit: UploadLimit| UploadLimitExceeded { filename: filename.to_string(), limit };
+
+        // Announced by the sender, who may claim anything up to u64::MAX
+        let size = file_info.f_len.max(stored_len);
+        let max = config.max_file_size.unwrap_or(MAX_FILE_SIZE);
+        if size > max {
+            return Err(refuse(UploadLimit::FileSize { size, max }).into());
+        }
+
+        if let (Some(max), Some(dir_path)) = (config.max_host_daily_size, out_path.parent()) {
+            let host = filename.split("||").next().unwrap_or("");
+            let mut used: u64 = 0;
+            let mut entries = fs::read_dir(dir_path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name();
+                if name.to_string_lossy().split("||").next() == Some(host) && name != filename {
+                    used = used.saturating_add(entry.metadata().await?.len());
+                }
+            }
+            if used.saturating_add(stored_len) > max {
+                return Err(refuse(UploadLimit::HostQuota { host: host.to_string(), used, size: stored_len, max }).into());
+            }
+        }
+
+        // A compressed upload and its decompressed log are both on disk until it is moved into place
+        let needed = if decompressing { file_info.f_len.saturating_add(stored_len) } else { file_info.f_len };
+        let available = log_utils::available_space(out_path.parent().unwrap_or(Path::new(".")))?;
+        if needed.saturating_add(config.min_free_space) > available {
+            return Err(refuse(UploadLimit::DiskSpace { needed, available: available.saturating_sub(config.min_free_space) }).into());
+        }
+        Ok(())
+    }
+
+    fn compressed_path(out_path: &Path, compression: Compression) -> PathBuf {
+        let mut path = out_path.as_os_str().to_owned();
+        path.push(compression.extension());
+        PathBuf::from(path)
+    }
+
+    // A fresh name in the partial upload directory, which is on the same filesystem as the logs
+    // so that the final rename is atomic
+    async fn temp_path() -> anyhow::Result<PathBuf> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let dir_path = PathBuf::from(log_utils::partial_upload_dir());
+        fs::create_dir_all(&dir_path).await?;
+        Ok(dir_path.join(format!("{}-{}.tmp", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed))))
+    }
+
+    // Opens the partial file for an upload id, hashing whatever an earlier attempt left in it
+    async fn open_partial(upload_id: &str, total_len: u64) -> anyhow::Result<(PathBuf, File, u64, Sha256)> {
+        let dir_path = PathBuf::from(log_utils::partial_upload_dir());
+        fs::create_dir_all(&dir_path).await?;
+        let path = dir_path.join(format!("{}.part", upload_id));
+
+        let mut hasher = Sha256::new();
+        let mut written = 0;
+        if let Ok(mut existing) = File::open(&path).await {
+            let mut buffer = vec![0u8; 64 * 1024];
+            loop {
+                let n = existing.read(&mut buffer).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..n]);
+                written += n as u64;
+            }
+        }
+        // Longer than the file it is meant to be, so it cannot be a prefix of it
+        if written > total_len {
+            fs::remove_file(&path).await?;
+            hasher = Sha256::new();
+            written = 0;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path).await?;
+        Ok((path, file, written, hasher))
+    }
+
+    pub async fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        if self.written + bytes.len() as u64 > self.total_len {
+            anyhow::bail!("received more than the {} bytes announced for {}", self.total_len, self.filename);
+        }
+        self.out_file.write_all(bytes).await?;
+        self.hasher.update(bytes);
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.written == self.total_len
+    }
+
+    pub fn is_resumable(&self) -> bool {
+        self.resumable
+    }
+
+    // Keeps an unfinished resumable upload for the sender's next attempt
+    pub async fn suspend(mut self) -> anyhow::Result<()> {
+        self.out_file.flush().await?;
+        self.out_file.sync_all().await?;
+        println!("Keeping {} of {} bytes of {} for a later attempt", self.written, self.total_len, self.filename);
+        Ok(())
+    }
+
+    // Drops an unfinished upload that cannot be resumed
+    pub async fn abort(self) -> anyhow::Result<()> {
+        drop(self.out_file);
+        fs::remove_file(&self.write_path).await?;
+        anyhow::bail!("connection closed after {} of {} bytes of {}", self.written, self.total_len, self.filename)
+    }
+
+    // Ends an upload the sender gave up on, keeping what arrived if it can be resumed
+    pub async fn cancel(self) -> anyhow::Result<()> {
+        if self.resumable {
+            return self.suspend().await;
+        }
+        drop(self.out_file);
+        fs::remove_file(&self.write_path).await?;
+        Ok(())
+    }
+
+    // Ends whatever the connection left unfinished
+    pub async fn close(self) -> anyhow::Result<()> {
+        if self.resumable {
+            self.suspend().await
+        } else {
+            self.abort().await
+        }
+    }
+
+    // Checks the file against the announced length and the sender's digest, then moves it into
+    // place, decompressing it first if needed, and records the server data it contains. The log
+    // only appears at out_path once it is complete and on disk. A file that does not match is
+    // deleted rather than kept.
+    pub async fn finish(mut self) -> anyhow::Result<PathBuf> {
+        if !self.is_complete() {
+            anyhow::bail!("connection closed after {} of {} bytes", self.written, self.total_len);
+        }
+        self.out_file.flush().await?;
+        self.out_file.sync_all().await?;
+        drop(self.out_file);
+
+        if let Some(expected) = self.expected_sha256 {
+            let actual: [u8; 32] = self.hasher.finalize().into();
+            if actual != expected {
+                fs::remove_file(&self.write_path).await?;
+                anyhow::bail!("checksum mismatch for {}", self.filename);
+            }
+        }
+
+        let stored_path = if self.compression == Compression::None || self.store_compressed {
+            let stored_path = IncomingFile::compressed_path(&self.out_path, self.compression);
+            fs::rename(&self.write_path, &stored_path).await?;
+            stored_path
+        } else {
+            let decompressed_path = IncomingFile::temp_path().await?;
+            let (compression, src, dst, limit) = (self.compression, self.write_path.clone(), decompressed_path.clone(), self.original_len);
+            let decompressed = tokio::task::spawn_blocking(move || compression.decompress_file(&src, &dst, limit)).await?;
+            fs::remove_file(&self.write_path).await?;
+            match decompressed {
+                Ok(len) if len == self.original_len => {}
+                Ok(len) => {
+                    fs::remove_file(&decompressed_path).await?;
+                    anyhow::bail!("{} decompressed to {} bytes, expected {}", self.filename, len, self.original_len);
+                }
+                Err(e) => {
+                    let _ = fs::remove_file(&decompressed_path).await;
+                    anyhow::bail!("decompressing {} failed: {}", self.filename, e);
+                }
+            }
+            fs::rename(&decompressed_path, &self.out_path).await?;
+            self.out_path.clone()
+        };
+        // Makes the rename itself durable
+        if let Some(dir_path) = stored_path.parent() {
+            File::open(dir_path).await?.sync_all().await?;
+        }
+        if self.compression != Compression::None {
+            println!(
+                "Received {} with {}: {} bytes sent for {} bytes of log (ratio {:.1})",
+                self.filename, self.compression.name(), self.total_len, self.original_len,
+                self.original_len as f64 / self.total_len.max(1) as f64
+            );
+        }
+
+        // Store server data to JSON file
+        println!("filename: {:?}, out_path: {:?}", self.filename, stored_path);
+        if stored_path == self.out_path {
+            log_utils::update_server_data(self.out_path.to_string_lossy().to_string());
+        } else {
+            let (compression, path, limit) = (self.compression, stored_path.clone(), self.original_len);
+            let content = tokio::task::spawn_blocking(move || compression.decompress(&std::fs::read(path)?, limit)).await??;
+            log_utils::update_server_data_from(&self.out_path.to_string_lossy(), &String::from_utf8_lossy(&content));
+        }
+
+        Ok(stored_path)
+    }
+}
+
+// Uploads are stored under the name the sender gives, so it must be a single plain path
+// component, e.g. "web-1.example.com||09:08:12.log". Surrounding whitespace is dropped.
+pub fn sanitise_filename(filename: &str) -> Result<String, FilenameError> {
+    let filename = filename.trim();
+    if filename.is_empty() {
+        return Err(FilenameError::Empty);
+    }
+    if filename.contains('\0') {
+        return Err(FilenameError::Nul);
+    }
+    if filename.starts_with('/') || filename.starts_with('\\') {
+        return Err(FilenameError::Absolute);
+    }
+    if filename.split(['/', '\\']).any(|component| component == "..") {
+        return Err(FilenameError::ParentDir);
+    }
+    if filename.contains(['/', '\\']) {
+        return Err(FilenameError::Separator);
+    }
+    if filename.starts_with('.') {
+        return Err(FilenameError::Hidden);
+    }
+    if let Some(c) = filename.chars().find(|c| !(c.is_ascii_alphanumeric() || "._-|:".contains(*c))) {
+        return Err(FilenameError::InvalidCharacter(c));
+    }
+    Ok(filename.to_string())
+}
+
+// Removes what earlier runs left behind: temporary files are never picked up again, and partial
+// uploads nobody has come back for in a week are given up on
+pub async fn clean_stale_uploads() -> anyhow::Result<()> {
+    let dir_path = PathBuf::from(log_utils::partial_upload_dir());
+    let Ok(mut entries) = fs::read_dir(&dir_path).await else {
+        return Ok(());
+    };
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let stale = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("tmp") => true,
+            Some("part") => entry.metadata().await?.modified()?.elapsed().unwrap_or_default() > STALE_PARTIAL_AGE,
+            _ => false,
+        };
+        if stale {
+            fs::remove_file(&path).await?;
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        println!("Removed {} stale upload files from {:?}", removed, dir_path);
+    }
+    Ok(())
+}
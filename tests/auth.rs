@@ -0,0 +1,118 @@
+/**
+ * Commands signed with a shared key, and a logging server that refuses any it cannot verify
+ */
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use lib_setup::auth::{CommandKey, CommandVerifier, MAX_CLOCK_SKEW};
+use lib_setup::client::Client;
+use lib_setup::error::AuthError;
+use lib_setup::logger_state::LoggerState;
+use lib_setup::message::{Command, FrameType, ReplyStatus};
+use lib_setup::server::Server;
+
+mod common;
+
+const VERSION: u8 = 5;
+
+// Signed by hand, as CommandKey::sign always uses the current time
+fn signed_at(key: &[u8], timestamp: u64, nonce: &str, request: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(format!("{} {} {} {} {}", VERSION, FrameType::Command as u8, timestamp, nonce, request).as_bytes());
+    let mac: String = mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("auth {} {} {} {}", timestamp, nonce, mac, request)
+}
+
+fn sign(key: &CommandKey, request: &str) -> String {
+    key.sign(VERSION, FrameType::Command, request).unwrap()
+}
+
+async fn verify<'a>(verifier: &CommandVerifier, text: &'a str) -> Result<&'a str, AuthError> {
+    verifier.verify(VERSION, FrameType::Command, text).await
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[tokio::test]
+async fn signed_command_is_accepted_once() {
+    let key = CommandKey::new("secret");
+    let verifier = CommandVerifier::new(key.clone());
+    let signed = sign(&key, "7 pause");
+    assert_eq!(verify(&verifier, &signed).await, Ok("7 pause"));
+    assert_eq!(verify(&verifier, &signed).await, Err(AuthError::Replayed));
+    // A new signature of the same command has its own nonce
+    assert_eq!(verify(&verifier, &sign(&key, "7 pause")).await, Ok("7 pause"));
+}
+
+#[tokio::test]
+async fn tampered_command_is_refused() {
+    let verifier = CommandVerifier::new(CommandKey::new("secret"));
+    let signed = sign(&CommandKey::new("secret"), "7 pause");
+    let tampered = signed.replace("7 pause", "7 resume");
+    assert_eq!(verify(&verifier, &tampered).await, Err(AuthError::BadSignature));
+
+    let other_key = sign(&CommandKey::new("guess"), "7 pause");
+    assert_eq!(verify(&verifier, &other_key).await, Err(AuthError::BadSignature));
+}
+
+#[tokio::test]
+async fn command_is_bound_to_its_frame() {
+    let verifier = CommandVerifier::new(CommandKey::new("secret"));
+    let signed = sign(&CommandKey::new("secret"), "7 pause");
+    assert_eq!(verifier.verify(4, FrameType::Command, &signed).await, Err(AuthError::BadSignature));
+    assert_eq!(verifier.verify(VERSION, FrameType::Reply, &signed).await, Err(AuthError::BadSignature));
+    assert_eq!(verify(&verifier, &signed).await, Ok("7 pause"));
+}
+
+#[tokio::test]
+async fn old_command_is_refused() {
+    let verifier = CommandVerifier::new(CommandKey::new("secret"));
+    let signed = signed_at(b"secret", now() - MAX_CLOCK_SKEW.as_secs() - 60, "0123456789abcdef", "7 pause");
+    assert!(matches!(verify(&verifier, &signed).await, Err(AuthError::Expired { .. })));
+
+    let recent = signed_at(b"secret", now() - 60, "0123456789abcdef", "7 pause");
+    assert_eq!(verify(&verifier, &recent).await, Ok("7 pause"));
+}
+
+#[tokio::test]
+async fn unsigned_command_is_refused() {
+    let verifier = CommandVerifier::new(CommandKey::new("secret"));
+    assert_eq!(verify(&verifier, "7 pause").await, Err(AuthError::Unsigned));
+    assert_eq!(verify(&verifier, "auth 1753427098 pause").await, Err(AuthError::Malformed));
+    assert_eq!(verify(&verifier, "auth soon 0123 abcd 7 pause").await, Err(AuthError::Malformed));
+    let not_hex = format!("auth {} 0123 zz 7 pause", now());
+    assert_eq!(verify(&verifier, &not_hex).await, Err(AuthError::Malformed));
+}
+
+#[tokio::test]
+async fn key_is_read_without_trailing_newline() {
+    let path = std::env::temp_dir().join(format!("auth_test_key_{}", std::process::id()));
+    std::fs::write(&path, "secret\n").unwrap();
+    let signed = sign(&CommandKey::from_file(&path).unwrap(), "7 pause");
+    let verifier = CommandVerifier::new(CommandKey::new("secret"));
+    assert_eq!(verify(&verifier, &signed).await, Ok("7 pause"));
+
+    std::fs::write(&path, " \n").unwrap();
+    assert!(CommandKey::from_file(&path).is_err());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn logging_server_requires_signed_commands() {
+    let key = CommandKey::new("secret");
+    let port = common::start_logging_server(Server::new("127.0.0.1", 0).with_auth(key.clone()), LoggerState::new()).await;
+
+    let mut unsigned = Client::connect("127.0.0.1", port).await.unwrap();
+    unsigned.handshake().await.unwrap();
+    let reply = unsigned.send_command(Command::Pause).await.unwrap();
+    assert_eq!(reply.status, ReplyStatus::Error(AuthError::Unsigned.to_string()));
+
+    let mut signed = Client::connect("127.0.0.1", port).await.unwrap().with_key(key);
+    signed.handshake().await.unwrap();
+    let reply = signed.send_command(Command::Status).await.unwrap();
+    assert!(matches!(reply.status, ReplyStatus::Payload(status) if status.contains("interval_secs")));
+    assert_eq!(signed.send_command(Command::Exit).await.unwrap().status, ReplyStatus::Ok);
+}
@@ -0,0 +1,55 @@
+/**
+ * Several files sent over one connection, with the server's account of the batch deciding
+ * what happened to each
+ */
+use std::path::Path;
+
+use lib_setup::central_state::CentralState;
+use lib_setup::client::Client;
+use lib_setup::datetime::DateTime;
+use lib_setup::message::ReplyStatus;
+use lib_setup::server::Server;
+
+mod common;
+
+fn report(dir: &Path, filename: &str) -> String {
+    common::write_report(dir, filename, &format!("RUNNING CONTAINERS: {}\n", filename).repeat(100))
+}
+
+#[tokio::test]
+async fn mixed_batch_after_a_single_file() {
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0), CentralState::new()).await;
+    let dir = common::temp_dir("batch");
+
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    assert!(client.handshake().await.unwrap().has("batch"));
+    let datetime = DateTime::now();
+
+    // Counted by the server in the next batch, as nothing closed one before it
+    client.send_file(report(&dir, "web-1||08:00:00.log"), datetime.clone()).await.unwrap();
+
+    let files = vec![
+        (report(&dir, "web-1||09:00:00.log"), datetime.clone()),
+        // Refused by its header
+        (report(&dir, "web-1||09 00 01.log"), datetime.clone()),
+        // Never sent
+        (dir.join("web-1||missing.log").to_string_lossy().to_string(), datetime.clone()),
+        (report(&dir, "web-1||09:00:02.log"), datetime.clone()),
+    ];
+    let results = client.send_files(files).await.unwrap();
+
+    let filenames: Vec<&str> = results.iter().map(|result| result.filename.as_str()).collect();
+    assert_eq!(filenames, vec!["web-1||09:00:00.log", "web-1||09 00 01.log", "web-1||missing.log", "web-1||09:00:02.log"]);
+    assert_eq!(results[0].status, ReplyStatus::Ok);
+    assert!(matches!(&results[1].status, ReplyStatus::Error(reason) if reason.contains("contains ' '")), "{:?}", results[1]);
+    assert!(matches!(&results[2].status, ReplyStatus::Error(reason) if reason.contains("File not valid")), "{:?}", results[2]);
+    assert_eq!(results[3].status, ReplyStatus::Ok);
+
+    for filename in ["web-1||08:00:00.log", "web-1||09:00:00.log", "web-1||09:00:02.log"] {
+        assert_eq!(std::fs::read(common::stored_path(&datetime, filename)).unwrap(), std::fs::read(dir.join(filename)).unwrap());
+    }
+    assert!(!common::stored_path(&datetime, "web-1||09 00 01.log").exists());
+
+    // The connection is still usable after the batch
+    client.heartbeat().await.unwrap();
+}
@@ -0,0 +1,81 @@
+/**
+ * CRC32C trailers on version 5 and later frames, and the header checksum of version 6 frames
+ */
+use lib_setup::constants::{CHECKSUM_SIZE, METADATA_SIZE_V4, METADATA_SIZE_V6};
+use lib_setup::error::{ChecksumMismatch, FrameError};
+use lib_setup::message::{FrameType, Message};
+use lib_setup::message_reader::MessageReader;
+
+#[test]
+fn trailer_covers_the_whole_frame() {
+    let encoded = Message::new("7 status").encode().unwrap();
+    let (frame, trailer) = encoded.split_at(encoded.len() - CHECKSUM_SIZE);
+    assert_eq!(trailer, crc32c::crc32c(frame).to_be_bytes());
+    assert_eq!(Message::decode(&encoded).unwrap().text(), "7 status");
+}
+
+#[test]
+fn flipped_bit_is_reported() {
+    let mut encoded = Message::with_kind(FrameType::FileChunk, b"RUNNING CONTAINERS: (3, 4)".to_vec()).encode().unwrap();
+    let content_end = encoded.len() - CHECKSUM_SIZE;
+    let expected = crc32c::crc32c(&encoded[..content_end]);
+    encoded[METADATA_SIZE_V6 + 2] ^= 0x01;
+    let actual = crc32c::crc32c(&encoded[..content_end]);
+    assert_eq!(
+        Message::decode(&encoded).unwrap_err(),
+        FrameError::Checksum(ChecksumMismatch { expected, actual })
+    );
+}
+
+#[test]
+fn damaged_trailer_is_reported() {
+    let mut encoded = Message::new("7 status").encode().unwrap();
+    let last = encoded.len() - 1;
+    encoded[last] ^= 0xff;
+    assert!(matches!(Message::decode(&encoded), Err(FrameError::Checksum(_))));
+}
+
+#[test]
+fn reader_drops_damaged_frame_and_continues() {
+    let mut damaged = Message::new("7 status").encode().unwrap();
+    damaged[METADATA_SIZE_V6 + 1] ^= 0x20;
+    let mut data = damaged.clone();
+    data.extend(Message::new("8 list").encode().unwrap());
+
+    let mut reader = MessageReader::new();
+    let messages = reader.read(&data);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].text(), "8 list");
+    assert_eq!(reader.dropped_frames, 1);
+    assert_eq!(reader.dropped_bytes, damaged.len() as u64);
+    assert!(matches!(reader.take_errors()[..], [FrameError::Checksum(_)]));
+}
+
+#[test]
+fn older_versions_have_no_trailer() {
+    let v4 = Message::new("7 status").with_version(4).encode().unwrap();
+    let v5 = Message::new("7 status").with_version(5).encode().unwrap();
+    assert_eq!(v5.len(), v4.len() + CHECKSUM_SIZE);
+    assert_eq!(&v5[3..v4.len()], &v4[3..]);
+}
+
+#[test]
+fn version_6_header_has_its_own_checksum() {
+    let v5 = Message::new("7 status").with_version(5).encode().unwrap();
+    let v6 = Message::new("7 status").encode().unwrap();
+    assert_eq!(v6.len(), v5.len() + METADATA_SIZE_V6 - METADATA_SIZE_V4);
+    let field = &v6[METADATA_SIZE_V4..METADATA_SIZE_V6];
+    assert_eq!(field, crc32c::crc32c(&v6[..METADATA_SIZE_V4]).to_be_bytes());
+    // The content follows as in version 5
+    assert_eq!(&v6[METADATA_SIZE_V6..v6.len() - CHECKSUM_SIZE], &v5[METADATA_SIZE_V4..v5.len() - CHECKSUM_SIZE]);
+}
+
+#[test]
+fn damaged_header_is_reported_before_the_content() {
+    let mut encoded = Message::new("7 status").encode().unwrap();
+    // Low byte of the length
+    encoded[METADATA_SIZE_V4 - 1] ^= 0x40;
+    // The header alone is enough to tell
+    assert!(matches!(Message::parse_header(&encoded[..METADATA_SIZE_V6]), Err(FrameError::HeaderChecksum(_))));
+    assert!(matches!(Message::decode(&encoded), Err(FrameError::HeaderChecksum(_))));
+}
@@ -0,0 +1,123 @@
+/**
+ * Text form of the commands understood by the logging server, the names older clients send,
+ * and how long a client waits for the answer to one
+ */
+use std::time::Duration;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpListener;
+use tokio_util::codec::Framed;
+
+use lib_setup::client::Client;
+use lib_setup::codec::MessageCodec;
+use lib_setup::error::ReplyTimedOut;
+use lib_setup::handshake::Hello;
+use lib_setup::message::{Command, Message, Request};
+
+#[test]
+fn encode_round_trip() {
+    let commands = [
+        Command::Exit,
+        Command::Collect { container: None },
+        Command::Collect { container: Some("web-1".to_string()) },
+        Command::Fetch { container: None },
+        Command::Fetch { container: Some("web-1".to_string()) },
+        Command::List,
+        Command::Pause,
+        Command::Resume,
+        Command::Status,
+        Command::SetInterval(Duration::from_secs(3600)),
+    ];
+    for command in commands {
+        assert_eq!(Command::decode(&command.encode()).unwrap(), command);
+    }
+}
+
+#[test]
+fn decode_legacy_names() {
+    assert_eq!(Command::decode("syslog").unwrap(), Command::Collect { container: None });
+    assert_eq!(Command::decode("stop").unwrap(), Command::Pause);
+    assert_eq!(Command::decode("start").unwrap(), Command::Resume);
+    assert_eq!(Command::decode("continue").unwrap(), Command::Resume);
+    // Legacy names are only sent to servers that predate replies
+    assert_eq!(Command::Pause.encode(), "pause");
+    assert_eq!(Command::Resume.encode(), "resume");
+}
+
+#[test]
+fn legacy_names_round_trip() {
+    let commands = [
+        Command::Exit,
+        Command::Collect { container: None },
+        Command::List,
+        Command::Pause,
+        Command::Resume,
+    ];
+    for command in commands {
+        assert_eq!(Command::decode(command.legacy_name().unwrap()).unwrap(), command);
+    }
+    assert_eq!(Command::Pause.legacy_name(), Some("stop"));
+    assert_eq!(Command::Status.legacy_name(), None);
+    assert_eq!(Command::Collect { container: Some("web-1".to_string()) }.legacy_name(), None);
+}
+
+#[test]
+fn decode_ignores_extra_whitespace() {
+    assert_eq!(
+        Command::decode("  collect   web-1 ").unwrap(),
+        Command::Collect { container: Some("web-1".to_string()) }
+    );
+}
+
+#[test]
+fn decode_rejects_bad_commands() {
+    assert!(Command::decode("").is_err());
+    assert!(Command::decode("reboot").is_err());
+    assert!(Command::decode("exit now").is_err());
+    assert!(Command::decode("collect web-1 web-2").is_err());
+    assert!(Command::decode("set_interval").is_err());
+    assert!(Command::decode("set_interval soon").is_err());
+    assert!(Command::decode("set_interval 0").is_err());
+}
+
+#[test]
+fn request_round_trip() {
+    let request = Request { id: 7, command: Command::Collect { container: Some("web-1".to_string()) } };
+    let message = Message::from_request(&request);
+    assert_eq!(message.text(), "7 collect web-1");
+    assert_eq!(message.to_request().unwrap(), request);
+
+    // Older clients send the bare command, which is answered with id 0
+    let legacy = Message::new("stop").to_request().unwrap();
+    assert_eq!(legacy, Request { id: 0, command: Command::Pause });
+}
+
+// Answers the hello, then reads commands without ever replying to them
+async fn start_silent_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut frames = Framed::new(socket, MessageCodec::new());
+                while let Some(Ok(message)) = frames.next().await {
+                    if let Some(hello) = Hello::from_message(&message) {
+                        let session = Hello::local().negotiate(&hello).unwrap();
+                        frames.send(Hello::from_session(&session).to_message()).await.unwrap();
+                    }
+                }
+            });
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn unanswered_command_times_out() {
+    let port = start_silent_server().await;
+    let after = Duration::from_millis(200);
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap().with_reply_timeout(after);
+    client.handshake().await.unwrap();
+    let e = client.send_command(Command::Status).await.unwrap_err();
+    assert_eq!(e.downcast::<ReplyTimedOut>().unwrap(), ReplyTimedOut { id: 1, after });
+}
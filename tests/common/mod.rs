@@ -0,0 +1,112 @@
+// Each test binary uses only some of these
+#![allow(dead_code)]
+/**
+ * Fixtures shared by the integration tests: where a test binary keeps its logs, and servers
+ * listening on ports picked by the system
+ */
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use lib_setup::central_state::CentralState;
+use lib_setup::datetime::DateTime;
+use lib_setup::log_utils;
+use lib_setup::logger_state::LoggerState;
+use lib_setup::server::Server;
+
+// A fresh directory for one test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("privacy_lock_test_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Where servers keep logs, set once for every test in the binary
+pub fn log_dir() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = temp_dir("logs");
+        log_utils::set_log_folder(&dir).unwrap();
+        dir
+    })
+}
+
+// Where the storing server keeps a report that arrived on datetime
+pub fn stored_path(datetime: &DateTime, filename: &str) -> PathBuf {
+    log_dir().join(&datetime.year).join(&datetime.month).join(&datetime.day).join(filename)
+}
+
+// Writes a report with content to dir, returning its path as the client takes it
+pub fn write_report(dir: &Path, filename: &str, content: &str) -> String {
+    let path = dir.join(filename);
+    std::fs::write(&path, content).unwrap();
+    path.to_string_lossy().to_string()
+}
+
+// Both return the port the server listens on. The listener is bound before they return, so
+// connections made straight away wait for the server rather than being refused.
+async fn bind() -> (TcpListener, u16) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    (listener, port)
+}
+
+pub async fn start_storing_server(server: Server, state: CentralState) -> u16 {
+    log_dir();
+    let (listener, port) = bind().await;
+    tokio::spawn(async move { server.run_storing_server_on(listener, state).await });
+    port
+}
+
+pub async fn start_logging_server(server: Server, state: LoggerState) -> u16 {
+    log_dir();
+    let (listener, port) = bind().await;
+    tokio::spawn(async move { server.run_logging_server_on(listener, state).await });
+    port
+}
+
+// Bytes that do not compress, so that as much is sent as the file holds
+pub fn incompressible(len: usize) -> Vec<u8> {
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+// Forwards one connection to the server on port, cutting it off once limit bytes have gone
+// towards the server. Finishes with the number of bytes forwarded once the server has closed
+// its side, i.e. once it is done with whatever the connection left unfinished.
+pub async fn proxy(port: u16, limit: usize) -> (u16, JoinHandle<usize>) {
+    let (listener, proxy_port) = bind().await;
+    let forwarding = tokio::spawn(async move {
+        let (client, _) = listener.accept().await.unwrap();
+        let server = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (mut client_read, mut client_write) = client.into_split();
+        let (mut server_read, mut server_write) = server.into_split();
+        let answers = tokio::spawn(async move { tokio::io::copy(&mut server_read, &mut client_write).await });
+
+        let mut forwarded = 0;
+        let mut buffer = vec![0u8; 16 * 1024];
+        while forwarded < limit {
+            let wanted = (limit - forwarded).min(buffer.len());
+            let n = client_read.read(&mut buffer[..wanted]).await.unwrap_or(0);
+            if n == 0 || server_write.write_all(&buffer[..n]).await.is_err() {
+                break;
+            }
+            forwarded += n;
+        }
+        // The server reads the end of the connection
+        drop(server_write);
+        let _ = answers.await;
+        forwarded
+    });
+    (proxy_port, forwarding)
+}
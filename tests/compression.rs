@@ -0,0 +1,58 @@
+/**
+ * Compression used for file transfers
+ */
+use lib_setup::compression::Compression;
+
+fn report() -> Vec<u8> {
+    "RUNNING CONTAINERS: (3, 4)\n{\"PID\": \"1\", \"COMMAND\": \"/sbin/init\"}\n".repeat(2000).into_bytes()
+}
+
+#[test]
+fn round_trip() {
+    let data = report();
+    for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+        let compressed = compression.compress(&data).unwrap();
+        assert_eq!(compression.decompress(&compressed, data.len() as u64).unwrap(), data, "{:?}", compression);
+        assert_eq!(Compression::from_u8(compression as u8), Some(compression));
+    }
+}
+
+#[test]
+fn shrinks_reports() {
+    let data = report();
+    for compression in [Compression::Gzip, Compression::Zstd] {
+        assert!(compression.compress(&data).unwrap().len() * 10 < data.len(), "{:?}", compression);
+    }
+}
+
+#[test]
+fn stops_at_limit() {
+    let data = report();
+    for compression in [Compression::Gzip, Compression::Zstd] {
+        let compressed = compression.compress(&data).unwrap();
+        assert!(compression.decompress(&compressed, data.len() as u64 - 1).is_err(), "{:?}", compression);
+    }
+}
+
+#[test]
+fn decompress_file_round_trip() {
+    let dir = std::env::temp_dir().join(format!("compression_test_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let data = report();
+    let (src, dst) = (dir.join("report.log.gz"), dir.join("report.log"));
+    std::fs::write(&src, Compression::Gzip.compress(&data).unwrap()).unwrap();
+
+    let written = Compression::Gzip.decompress_file(&src, &dst, data.len() as u64).unwrap();
+    assert_eq!(written, data.len() as u64);
+    assert_eq!(std::fs::read(&dst).unwrap(), data);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn no_limit() {
+    let data = report();
+    for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+        let compressed = compression.compress(&data).unwrap();
+        assert_eq!(compression.decompress(&compressed, u64::MAX).unwrap(), data, "{:?}", compression);
+    }
+}
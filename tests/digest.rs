@@ -0,0 +1,98 @@
+/**
+ * Uploads checked against the SHA-256 digest in their header: a file that does not match is
+ * refused with a negative ack and never stored
+ */
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::Framed;
+
+use lib_setup::central_state::CentralState;
+use lib_setup::client::Client;
+use lib_setup::codec::MessageCodec;
+use lib_setup::datetime::DateTime;
+use lib_setup::error::UploadRejected;
+use lib_setup::file_info::FileInfo;
+use lib_setup::message::{FrameType, Message, ReplyStatus};
+use lib_setup::server::Server;
+
+mod common;
+
+const WRONG_DIGEST: [u8; 32] = [0xab; 32];
+
+// Nothing stored under filename, in whatever form the server would keep it
+fn nothing_stored(datetime: &DateTime, filename: &str) -> bool {
+    let dir = common::stored_path(datetime, filename).parent().unwrap().to_path_buf();
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return true;
+    };
+    !entries.map(|entry| entry.unwrap().file_name().to_string_lossy().to_string()).any(|name| name.starts_with(filename))
+}
+
+// Forwards one session to the server on port, replacing the digest of every file header
+async fn swap_digests(port: u16) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (client, _) = listener.accept().await.unwrap();
+        let server = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut client = Framed::new(client, MessageCodec::new());
+        let mut server = Framed::new(server, MessageCodec::new());
+        loop {
+            tokio::select! {
+                Some(Ok(mut message)) = client.next() => {
+                    if message.kind == FrameType::FileHeader {
+                        let file_info = FileInfo::decode(&message.content).unwrap().with_sha256(WRONG_DIGEST);
+                        message.content = Bytes::from(file_info.encode());
+                        message.length = message.content.len() as u32;
+                    }
+                    server.send(message).await.unwrap();
+                }
+                Some(Ok(message)) = server.next() => client.send(message).await.unwrap(),
+                else => break,
+            }
+        }
+    });
+    proxy_port
+}
+
+#[tokio::test]
+async fn file_not_matching_its_digest_is_refused() {
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0), CentralState::new()).await;
+    let content = b"RUNNING CONTAINERS: 2\n".repeat(100);
+    let datetime = DateTime::now();
+
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    assert!(client.handshake().await.unwrap().has("file_ack"));
+    let file_info = FileInfo::new(content.len() as u64, "web-1||08:00:00.log".to_string(), datetime.clone())
+        .with_sha256(WRONG_DIGEST);
+    client.send_message(Message::with_kind(FrameType::FileHeader, file_info.encode())).await.unwrap();
+    client.send_message(Message::with_kind(FrameType::FileChunk, content)).await.unwrap();
+
+    let ack = client.recv().await.unwrap();
+    assert_eq!(ack.kind, FrameType::FileAck);
+    let ReplyStatus::Error(reason) = ack.to_file_ack().unwrap() else {
+        panic!("file was accepted");
+    };
+    assert!(reason.contains("checksum mismatch"), "{}", reason);
+    assert!(nothing_stored(&datetime, "web-1||08:00:00.log"));
+}
+
+#[tokio::test]
+async fn sender_is_told_its_file_was_rejected() {
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0), CentralState::new()).await;
+    let dir = common::temp_dir("digest");
+    let path = common::write_report(&dir, "web-1||08:00:01.log", &"RUNNING CONTAINERS: 3\n".repeat(100));
+    let datetime = DateTime::now();
+
+    let mut client = Client::connect("127.0.0.1", swap_digests(port).await).await.unwrap();
+    client.handshake().await.unwrap();
+    let e = client.send_file(path, datetime.clone()).await.unwrap_err();
+
+    let rejected = e.downcast::<UploadRejected>().unwrap();
+    assert_eq!(rejected.filename, "web-1||08:00:01.log");
+    assert!(rejected.reason.contains("checksum mismatch"), "{}", rejected.reason);
+    // Sending it again may well succeed
+    assert!(!rejected.permanent);
+    assert!(nothing_stored(&datetime, "web-1||08:00:01.log"));
+}
@@ -0,0 +1,202 @@
+/**
+ * Round trips and rejections for the file transfer header
+ */
+use lib_setup::compression::Compression;
+use lib_setup::datetime::DateTime;
+use lib_setup::error::FileInfoError;
+use lib_setup::file_info::{FileInfo, FILE_TYPE, FILE_TYPE_EXT, MAX_FILENAME_LEN, MAX_UPLOAD_ID_LEN, TAG_COMPRESSION, TAG_SHA256, TAG_UPLOAD_ID};
+
+fn sample() -> FileInfo {
+    let datetime = DateTime {
+        year: "2025".to_string(),
+        month: "07".to_string(),
+        day: "25".to_string(),
+        time: "07:04:58".to_string(),
+    };
+    FileInfo::new(1234, "host||07:04:58.log".to_string(), datetime)
+}
+
+// Header with the given filename and datetime bytes, lengths taken from them
+fn raw_header(filename: &[u8], datetime: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![FILE_TYPE];
+    buffer.extend(10u64.to_be_bytes());
+    buffer.extend((filename.len() as u16).to_be_bytes());
+    buffer.extend(filename);
+    buffer.extend((datetime.len() as u32).to_be_bytes());
+    buffer.extend(datetime);
+    buffer
+}
+
+#[test]
+fn decode_round_trip() {
+    let info = sample();
+    assert_eq!(FileInfo::decode(&info.encode()).unwrap(), info);
+}
+
+#[test]
+fn decode_current_datetime() {
+    let info = FileInfo::new(0, "syslog.log".to_string(), DateTime::now());
+    assert_eq!(FileInfo::decode(&info.encode()).unwrap(), info);
+}
+
+#[test]
+fn decode_prefix_leaves_file_bytes() {
+    let info = sample();
+    let mut buffer = info.encode();
+    let header_len = buffer.len();
+    buffer.extend(b"file contents");
+
+    let (decoded, used) = FileInfo::decode_prefix(&buffer).unwrap().unwrap();
+    assert_eq!(decoded, info);
+    assert_eq!(used, header_len);
+}
+
+#[test]
+fn decode_prefix_waits_for_whole_header() {
+    let encoded = sample().encode();
+    for end in 0..encoded.len() {
+        assert!(FileInfo::decode_prefix(&encoded[..end]).unwrap().is_none(), "{} bytes", end);
+    }
+    assert!(matches!(FileInfo::decode(&encoded[..encoded.len() - 1]), Err(FileInfoError::Incomplete)));
+}
+
+#[test]
+fn rejects_unknown_type() {
+    let mut encoded = sample().encode();
+    encoded[0] = 7;
+    assert!(matches!(FileInfo::decode(&encoded), Err(FileInfoError::UnsupportedType(7))));
+}
+
+#[test]
+fn rejects_bad_filenames() {
+    let datetime = b"2025 07 25 07:04:58";
+    assert!(matches!(FileInfo::decode(&raw_header(b"", datetime)), Err(FileInfoError::EmptyFilename)));
+    assert!(matches!(FileInfo::decode(&raw_header(&[0xff, 0xfe], datetime)), Err(FileInfoError::InvalidFilename)));
+
+    // Rejected from the length field alone, before the name arrives
+    let long = vec![b'a'; MAX_FILENAME_LEN + 1];
+    let header = raw_header(&long, datetime);
+    assert!(matches!(
+        FileInfo::decode_prefix(&header[..11]),
+        Err(FileInfoError::FilenameTooLong { length, .. }) if length == MAX_FILENAME_LEN + 1
+    ));
+}
+
+#[test]
+fn rejects_bad_datetimes() {
+    for datetime in ["", "2025 07 25", "2025 13 25 07:04:58", "2025 07 25 07:04", "20x5 07 25 07:04:58", "2025 07 25 07:04:58 UTC"] {
+        let header = raw_header(b"syslog.log", datetime.as_bytes());
+        assert!(matches!(FileInfo::decode(&header), Err(FileInfoError::InvalidDateTime(_))), "{:?}", datetime);
+    }
+
+    let mut header = raw_header(b"syslog.log", b"");
+    let dt_len = header.len() - 4;
+    header[dt_len..].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(matches!(FileInfo::decode_prefix(&header), Err(FileInfoError::DateTimeTooLong { .. })));
+}
+
+#[tokio::test]
+async fn read_from_round_trip() {
+    let info = sample();
+    let mut buffer = info.encode();
+    buffer.extend(b"file contents");
+
+    let mut reader = &buffer[..];
+    assert_eq!(FileInfo::read_from(&mut reader).await.unwrap(), info);
+    // The file bytes are left for the caller
+    assert_eq!(reader, b"file contents");
+}
+
+#[tokio::test]
+async fn read_from_matches_decode_errors() {
+    let encoded = sample().encode();
+    let mut reader = &encoded[..encoded.len() - 1];
+    assert!(matches!(FileInfo::read_from(&mut reader).await, Err(FileInfoError::Incomplete)));
+
+    let header = raw_header(b"syslog.log", b"not a datetime");
+    let mut reader = &header[..];
+    assert!(matches!(FileInfo::read_from(&mut reader).await, Err(FileInfoError::InvalidDateTime(_))));
+
+    let long = vec![b'a'; MAX_FILENAME_LEN + 1];
+    let header = raw_header(&long, b"");
+    let mut reader = &header[..11];
+    assert!(matches!(FileInfo::read_from(&mut reader).await, Err(FileInfoError::FilenameTooLong { .. })));
+}
+
+#[test]
+fn sha256_extension_round_trip() {
+    let info = sample().with_sha256([7; 32]);
+    let encoded = info.encode();
+    assert_eq!(encoded[0], FILE_TYPE_EXT);
+    assert_eq!(FileInfo::decode(&encoded).unwrap(), info);
+
+    for end in 0..encoded.len() {
+        assert!(FileInfo::decode_prefix(&encoded[..end]).unwrap().is_none(), "{} bytes", end);
+    }
+}
+
+#[tokio::test]
+async fn read_from_reads_extensions() {
+    let info = sample().with_sha256([7; 32]);
+    let mut buffer = info.encode();
+    buffer.extend(b"file contents");
+
+    let mut reader = &buffer[..];
+    assert_eq!(FileInfo::read_from(&mut reader).await.unwrap(), info);
+    assert_eq!(reader, b"file contents");
+}
+
+#[test]
+fn skips_unknown_extensions() {
+    let mut encoded = sample().encode();
+    encoded[0] = FILE_TYPE_EXT;
+    let extensions = [200, 0, 2, 1, 2, TAG_SHA256, 0, 32].iter().copied().chain([9; 32]).collect::<Vec<u8>>();
+    encoded.extend((extensions.len() as u16).to_be_bytes());
+    encoded.extend(extensions);
+
+    assert_eq!(FileInfo::decode(&encoded).unwrap().sha256, Some([9; 32]));
+}
+
+#[test]
+fn rejects_malformed_extensions() {
+    for extensions in [vec![TAG_SHA256, 0, 3, 1, 2, 3], vec![TAG_SHA256, 0, 32, 1], vec![TAG_SHA256]] {
+        let mut encoded = sample().encode();
+        encoded[0] = FILE_TYPE_EXT;
+        encoded.extend((extensions.len() as u16).to_be_bytes());
+        encoded.extend(&extensions);
+        assert!(matches!(FileInfo::decode(&encoded), Err(FileInfoError::InvalidExtension(TAG_SHA256))), "{:?}", extensions);
+    }
+}
+
+#[test]
+fn upload_id_round_trip() {
+    let info = sample().with_sha256([7; 32]).with_upload_id("3f2a-upload_1");
+    assert_eq!(FileInfo::decode(&info.encode()).unwrap(), info);
+}
+
+#[test]
+fn rejects_unsafe_upload_ids() {
+    let too_long = "a".repeat(MAX_UPLOAD_ID_LEN + 1);
+    for upload_id in ["", "../../etc/passwd", "a b", too_long.as_str()] {
+        let encoded = sample().with_upload_id(upload_id).encode();
+        assert!(matches!(FileInfo::decode(&encoded), Err(FileInfoError::InvalidExtension(TAG_UPLOAD_ID))), "{:?}", upload_id);
+    }
+}
+
+#[test]
+fn compression_round_trip() {
+    let info = sample().with_compression(Compression::Zstd, 98765).with_sha256([7; 32]);
+    let decoded = FileInfo::decode(&info.encode()).unwrap();
+    assert_eq!(decoded, info);
+    assert_eq!(decoded.original_len, 98765);
+}
+
+#[test]
+fn rejects_unknown_compression() {
+    let mut encoded = sample().encode();
+    encoded[0] = FILE_TYPE_EXT;
+    let extensions = [TAG_COMPRESSION, 0, 9, 42, 0, 0, 0, 0, 0, 0, 0, 1];
+    encoded.extend((extensions.len() as u16).to_be_bytes());
+    encoded.extend(extensions);
+    assert!(matches!(FileInfo::decode(&encoded), Err(FileInfoError::InvalidExtension(TAG_COMPRESSION))));
+}
@@ -0,0 +1,54 @@
+/**
+ * Filename checks applied to uploads before anything is written
+ */
+use lib_setup::error::FilenameError;
+use lib_setup::upload::sanitise_filename;
+
+#[test]
+fn accepts_report_names() {
+    for filename in ["syslog.log", "web-1.example.com||09:08:12.log", "report_2025-07-25.log"] {
+        assert_eq!(sanitise_filename(filename).unwrap(), filename);
+    }
+}
+
+#[test]
+fn trims_surrounding_whitespace() {
+    assert_eq!(sanitise_filename("  syslog.log\n").unwrap(), "syslog.log");
+    assert!(matches!(sanitise_filename(" \t "), Err(FilenameError::Empty)));
+}
+
+#[test]
+fn rejects_absolute_paths() {
+    for filename in ["/etc/cron.d/evil", "\\evil.log", "/"] {
+        assert!(matches!(sanitise_filename(filename), Err(FilenameError::Absolute)), "{:?}", filename);
+    }
+}
+
+#[test]
+fn rejects_parent_components() {
+    for filename in ["..", "../../etc/cron.d/evil", "logs/../evil.log", "..\\evil.log"] {
+        assert!(matches!(sanitise_filename(filename), Err(FilenameError::ParentDir)), "{:?}", filename);
+    }
+}
+
+#[test]
+fn rejects_nul_bytes() {
+    for filename in ["evil\0.log", "\0", "syslog.log\0/../x"] {
+        assert!(matches!(sanitise_filename(filename), Err(FilenameError::Nul)), "{:?}", filename);
+    }
+}
+
+#[test]
+fn rejects_nested_and_hidden_names() {
+    assert!(matches!(sanitise_filename("logs/syslog.log"), Err(FilenameError::Separator)));
+    assert!(matches!(sanitise_filename("logs\\syslog.log"), Err(FilenameError::Separator)));
+    assert!(matches!(sanitise_filename(".bashrc"), Err(FilenameError::Hidden)));
+    assert!(matches!(sanitise_filename("."), Err(FilenameError::Hidden)));
+}
+
+#[test]
+fn rejects_characters_outside_the_set() {
+    for (filename, c) in [("sys log.log", ' '), ("*.log", '*'), ("évil.log", 'é'), ("a$b", '$')] {
+        assert!(matches!(sanitise_filename(filename), Err(FilenameError::InvalidCharacter(found)) if found == c), "{:?}", filename);
+    }
+}
@@ -0,0 +1,267 @@
+/**
+ * Version negotiation between peers, and frames of the older versions they may fall back to
+ */
+use std::time::Duration;
+use futures::StreamExt;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tokio_util::codec::FramedRead;
+
+use lib_setup::client::Client;
+use lib_setup::codec::{FileFrame, FileInfoCodec};
+use lib_setup::constants::{CAPABILITIES, LEGACY_VERSION, PROTOCOL_VERSION};
+use lib_setup::datetime::DateTime;
+use lib_setup::error::FrameError;
+use lib_setup::file_info::FILE_TYPE;
+use lib_setup::handshake::{Hello, Session};
+use lib_setup::logger_state::LoggerState;
+use lib_setup::message::{Command, FrameType, Message, ReplyStatus};
+use lib_setup::server::Server;
+
+mod common;
+
+fn hello(min_version: u8, max_version: u8, capabilities: &[&str]) -> Hello {
+    Hello {
+        min_version,
+        max_version,
+        capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+    }
+}
+
+#[test]
+fn negotiate_highest_common_version() {
+    let local = hello(1, 5, &["replies", "file_ack", "zstd"]);
+    let peer = hello(2, 3, &["zstd", "replies", "gzip"]);
+    let session = local.negotiate(&peer).unwrap();
+    assert_eq!(session.version, 3);
+    // In local order, only what both offer
+    assert_eq!(session.capabilities, vec!["replies".to_string(), "zstd".to_string()]);
+    assert_eq!(peer.negotiate(&local).unwrap().version, 3);
+}
+
+#[test]
+fn negotiate_without_common_version() {
+    let local = hello(1, 2, &["replies"]);
+    let peer = hello(4, 5, &["replies"]);
+    let e = local.negotiate(&peer).unwrap_err();
+    assert!(e.to_string().contains("No common protocol version"), "{}", e);
+    assert!(peer.negotiate(&local).is_err());
+}
+
+#[test]
+fn answer_names_a_single_version() {
+    let session = Session { version: 4, capabilities: vec!["replies".to_string()] };
+    let answer = Hello::from_session(&session);
+    assert_eq!(answer, hello(4, 4, &["replies"]));
+    assert_eq!(Hello::local().negotiate(&answer).unwrap(), session);
+}
+
+#[test]
+fn hello_round_trip() {
+    let local = Hello::local();
+    assert_eq!(local.max_version, PROTOCOL_VERSION);
+    assert_eq!(local.capabilities.len(), CAPABILITIES.len());
+
+    // Hellos use the legacy framing so that any peer can read them
+    let message = local.to_message();
+    assert_eq!(message.version, LEGACY_VERSION);
+    let decoded = Message::decode(&message.encode().unwrap()).unwrap();
+    assert_eq!(Hello::from_message(&decoded), Some(local));
+
+    let bare = hello(1, 3, &[]);
+    assert_eq!(bare.to_message().text(), "hello 1 3");
+    assert_eq!(Hello::from_message(&bare.to_message()), Some(bare));
+}
+
+#[test]
+fn from_message_ignores_other_text() {
+    assert_eq!(Hello::from_message(&Message::new("7 status")), None);
+    assert_eq!(Hello::from_message(&Message::new("hello")), None);
+    assert_eq!(Hello::from_message(&Message::new("hello one two")), None);
+
+    // Only commands are hellos, whatever other frames carry
+    for kind in [FrameType::FileChunk, FrameType::LogLine, FrameType::Reply] {
+        assert_eq!(Hello::from_message(&Message::with_kind(kind, "hello 1 5 replies")), None);
+    }
+}
+
+#[test]
+fn legacy_session() {
+    let session = Session::legacy();
+    assert_eq!(session.version, LEGACY_VERSION);
+    assert!(!session.has("replies"));
+}
+
+#[test]
+fn version_1_frame() {
+    let message = Message::new("status").with_version(1);
+    let encoded = message.encode().unwrap();
+    // Version, u16 length, content
+    assert_eq!(&encoded[..3], &[1, 0, 6]);
+    assert_eq!(&encoded[3..], b"status");
+
+    let decoded = Message::decode(&encoded).unwrap();
+    assert_eq!((decoded.version, decoded.kind, decoded.text()), (1, FrameType::Command, "status"));
+}
+
+#[test]
+fn version_2_frame() {
+    let message = Message::new("status").with_version(2);
+    let encoded = message.encode().unwrap();
+    // Version, u32 length, content
+    assert_eq!(&encoded[..5], &[2, 0, 0, 0, 6]);
+    assert_eq!(&encoded[5..], b"status");
+
+    let decoded = Message::decode(&encoded).unwrap();
+    assert_eq!((decoded.version, decoded.kind, decoded.text()), (2, FrameType::Command, "status"));
+}
+
+#[test]
+fn old_versions_limit_what_is_sent() {
+    let long = Message::new("x".repeat(u16::MAX as usize + 1));
+    assert!(matches!(
+        long.clone().with_version(1).encode(),
+        Err(FrameError::ContentTooLong { version: 1, .. })
+    ));
+    assert!(long.with_version(2).encode().is_ok());
+
+    // Frames before version 4 have no type byte, so only carry commands and replies
+    let heartbeat = Message::heartbeat().with_version(2);
+    assert_eq!(
+        heartbeat.encode().unwrap_err(),
+        FrameError::UnsupportedFrameType { kind: FrameType::Heartbeat as u8, version: 2 }
+    );
+}
+
+#[tokio::test]
+async fn client_and_server_agree() {
+    let state = LoggerState::new();
+    let port = common::start_logging_server(Server::new("127.0.0.1", 0), state.clone()).await;
+
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    let session = client.handshake().await.unwrap();
+    let mut expected = Hello::local().negotiate(&Hello::local()).unwrap();
+    // Only offered by clients built with_commands
+    expected.capabilities.retain(|c| c != "commands");
+    assert_eq!(session, expected);
+    let reply = client.send_command(Command::Status).await.unwrap();
+    assert!(matches!(reply.status, ReplyStatus::Payload(_)));
+
+    // A client from before the handshake sends bare names, and is answered in the legacy framing
+    let mut legacy = Client::connect("127.0.0.1", port).await.unwrap();
+    assert_eq!(legacy.session, Session::legacy());
+    legacy.send_message(Message::new("stop")).await.unwrap();
+    assert_eq!(legacy.wait_reply(0).await.unwrap().status, ReplyStatus::Ok);
+    assert!(!*state.running.lock().await);
+}
+
+// Behaves like a logging server from before replies: version 1 frames whose whole text is
+// matched against the original command names, and nothing ever written back. Returns its port
+// and the commands it acted on.
+async fn start_legacy_logging_server() -> (u16, mpsc::Receiver<String>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (received, commands) = mpsc::channel(8);
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let received = received.clone();
+            tokio::spawn(async move {
+                let mut buffer = Vec::new();
+                loop {
+                    let mut chunk = [0u8; 256];
+                    match socket.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+                    }
+                    while buffer.len() >= 3 && buffer.len() >= 3 + u16::from_be_bytes([buffer[1], buffer[2]]) as usize {
+                        let frame: Vec<u8> = buffer.drain(..3 + u16::from_be_bytes([buffer[1], buffer[2]]) as usize).collect();
+                        // Anything but version 1 failed to decode and dropped the connection
+                        if frame[0] != LEGACY_VERSION {
+                            return;
+                        }
+                        let content = String::from_utf8(frame[3..].to_vec()).unwrap();
+                        if matches!(content.as_str(), "exit" | "syslog" | "list" | "start" | "continue" | "stop" | "pause") {
+                            received.send(content.clone()).await.unwrap();
+                        }
+                        if content == "exit" {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    (port, commands)
+}
+
+#[tokio::test]
+async fn new_client_and_server_without_replies() {
+    let (port, mut commands) = start_legacy_logging_server().await;
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    assert_eq!(client.handshake().await.unwrap(), Session::legacy());
+
+    // Sent by their original names and taken as done, as nothing will answer them
+    for command in [Command::Pause, Command::Resume, Command::Collect { container: None }, Command::List, Command::Exit] {
+        let reply = tokio::time::timeout(Duration::from_secs(1), client.send_command(command)).await.unwrap();
+        assert_eq!(reply.unwrap().status, ReplyStatus::Ok);
+    }
+    let mut received = Vec::new();
+    for _ in 0..5 {
+        received.push(commands.recv().await.unwrap());
+    }
+    assert_eq!(received, vec!["stop", "start", "syslog", "list", "exit"]);
+}
+
+#[tokio::test]
+async fn command_without_a_legacy_name_is_not_sent() {
+    let (port, _commands) = start_legacy_logging_server().await;
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    assert_eq!(client.handshake().await.unwrap(), Session::legacy());
+    assert!(client.send_command(Command::Status).await.is_err());
+}
+
+// Behaves like a storing server from before the handshake: one bare file per connection, and
+// anything that does not start like a file header is hung up on. Returns its port and the files
+// it received.
+async fn start_legacy_server() -> (u16, mpsc::Receiver<(String, Vec<u8>)>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (received, files) = mpsc::channel(4);
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut first = [0u8; 1];
+            if socket.peek(&mut first).await.unwrap() == 0 || first[0] != FILE_TYPE {
+                continue;
+            }
+            let mut frames = FramedRead::new(socket, FileInfoCodec::new());
+            let Some(Ok(FileFrame::Header(file_info))) = frames.next().await else {
+                continue;
+            };
+            let mut content = Vec::new();
+            while (content.len() as u64) < file_info.f_len {
+                match frames.next().await {
+                    Some(Ok(FileFrame::Chunk(chunk))) => content.extend_from_slice(&chunk),
+                    _ => break,
+                }
+            }
+            received.send((file_info.filename, content)).await.unwrap();
+        }
+    });
+    (port, files)
+}
+
+#[tokio::test]
+async fn server_that_hangs_up_on_the_hello() {
+    let (port, mut files) = start_legacy_server().await;
+    let report = common::write_report(&common::temp_dir("legacy_storing"), "web-1||09:08:12.log", "RUNNING CONTAINERS: (3, 4)\n");
+
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    assert_eq!(client.handshake().await.unwrap(), Session::legacy());
+    client.send_file(report.clone(), DateTime::now()).await.unwrap();
+
+    let (filename, content) = files.recv().await.unwrap();
+    assert_eq!(filename, "web-1||09:08:12.log");
+    assert_eq!(content, std::fs::read(&report).unwrap());
+}
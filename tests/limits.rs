@@ -0,0 +1,73 @@
+/**
+ * How many connections and transfers the storing server takes at once
+ */
+use std::net::{IpAddr, Ipv4Addr};
+
+use lib_setup::central_state::CentralState;
+use lib_setup::client::Client;
+use lib_setup::datetime::DateTime;
+use lib_setup::error::{UploadLimit, UploadLimitExceeded};
+use lib_setup::file_info::FileInfo;
+use lib_setup::limits::{Admission, ConnectionLimits, Refusal};
+use lib_setup::message::{FrameType, Message, ReplyStatus};
+use lib_setup::server::Server;
+
+mod common;
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+#[tokio::test]
+async fn connections_and_transfers_are_counted_apart() {
+    let admission = Admission::new(ConnectionLimits { max_connections: 2, max_transfers: 1, ..ConnectionLimits::default() });
+    let first = admission.admit(LOCALHOST).await.unwrap();
+    let _second = admission.admit(LOCALHOST).await.unwrap();
+    assert_eq!(admission.admit(LOCALHOST).await.unwrap_err(), Refusal::Busy);
+
+    let transfer = admission.start_transfer().unwrap();
+    assert_eq!(admission.start_transfer().unwrap_err(), Refusal::Busy);
+    drop(transfer);
+    assert!(admission.start_transfer().is_ok());
+
+    drop(first);
+    assert!(admission.admit(LOCALHOST).await.is_ok());
+}
+
+#[tokio::test]
+async fn connecting_too_often_is_refused() {
+    let admission = Admission::new(ConnectionLimits { connect_burst: 2.0, connect_rate: 0.01, ..ConnectionLimits::default() });
+    let _first = admission.admit(LOCALHOST).await.unwrap();
+    let _second = admission.admit(LOCALHOST).await.unwrap();
+    assert_eq!(admission.admit(LOCALHOST).await.unwrap_err(), Refusal::RateLimited);
+    // Other addresses have their own allowance
+    assert!(admission.admit(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))).await.is_ok());
+}
+
+#[tokio::test]
+async fn idle_sessions_do_not_hold_transfers() {
+    let state = CentralState::new();
+    let server = Server::new("127.0.0.1", 0).with_limits(ConnectionLimits { max_transfers: 1, ..ConnectionLimits::default() });
+    let port = common::start_storing_server(server, state.clone()).await;
+    let report = common::write_report(&common::temp_dir("limits"), "web-1||09:08:12.log", "RUNNING CONTAINERS: (3, 4)\n");
+
+    // Sessions waiting between files leave the transfer free
+    let mut idle = Client::connect("127.0.0.1", port).await.unwrap();
+    idle.handshake().await.unwrap();
+    let mut sender = Client::connect("127.0.0.1", port).await.unwrap();
+    sender.handshake().await.unwrap();
+    sender.send_file(report.clone(), DateTime::now()).await.unwrap();
+
+    // A file whose header arrived holds it until the file ends
+    let header = FileInfo::new(1000, "web-1||09:08:13.log".to_string(), DateTime::now());
+    idle.send_message(Message::with_kind(FrameType::FileHeader, header.encode())).await.unwrap();
+    idle.heartbeat().await.unwrap();
+    let e = sender.send_file(report.clone(), DateTime::now()).await.unwrap_err();
+    let exceeded = e.downcast::<UploadLimitExceeded>().unwrap();
+    assert_eq!(exceeded.limit, UploadLimit::Busy { max: 1 });
+    assert!(exceeded.limit.is_transient());
+    assert_eq!(state.connections.lock().await.rejected_transfers, 1);
+
+    // Cancelling the file frees it
+    idle.send_message(Message::file_ack(&ReplyStatus::Error("cancelled".to_string()))).await.unwrap();
+    idle.heartbeat().await.unwrap();
+    sender.send_file(report, DateTime::now()).await.unwrap();
+}
@@ -0,0 +1,129 @@
+/**
+ * Lines streamed by a logger over its session: stored in the host's live log, passed on to
+ * watchers of GET /live/<host> and kept apart from the reports GET /logs lists
+ */
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use lib_setup::central_state::{start_http_server_on, CentralState};
+use lib_setup::client::Client;
+use lib_setup::datetime::DateTime;
+use lib_setup::message::LiveLine;
+use lib_setup::server::Server;
+use lib_setup::upload::UploadConfig;
+
+mod common;
+
+// On a runtime of its own, as the server's future cannot be shown to be Send to tokio::spawn
+fn start_http_server(state: CentralState) -> u16 {
+    common::log_dir();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    listener.set_nonblocking(true).unwrap();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async move {
+            let listener = TcpListener::from_std(listener).unwrap();
+            start_http_server_on(listener, state, UploadConfig::default(), None, None).await
+        });
+    });
+    port
+}
+
+async fn streaming_client(port: u16) -> Client {
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    assert!(client.handshake().await.unwrap().has("live"));
+    client
+}
+
+fn line(host: &str, line: &str) -> LiveLine {
+    LiveLine { host: host.to_string(), line: line.to_string() }
+}
+
+// Where the central server keeps the lines host sent today
+fn live_path(host: &str) -> std::path::PathBuf {
+    common::stored_path(&DateTime::now(), &format!("{}.live", host))
+}
+
+async fn get(port: u16, path: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn streamed_lines_are_stored_and_passed_on() {
+    let state = CentralState::new();
+    let mut lines = state.live.subscribe();
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0), state).await;
+
+    let mut client = streaming_client(port).await;
+    assert!(client.send_log_line(&line("web-live-1", "first")).await.unwrap());
+    assert!(client.send_log_line(&line("web-live-1", "second")).await.unwrap());
+
+    for expected in ["first", "second"] {
+        let received = tokio::time::timeout(Duration::from_secs(5), lines.recv()).await.unwrap().unwrap();
+        assert_eq!(received, line("web-live-1", expected));
+    }
+    // Stored before it is passed on
+    assert_eq!(std::fs::read_to_string(live_path("web-live-1")).unwrap(), "first\nsecond\n");
+}
+
+#[tokio::test]
+async fn live_logs_are_not_listed_as_reports() {
+    let state = CentralState::new();
+    let mut lines = state.live.subscribe();
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0), state.clone()).await;
+    let http_port = start_http_server(state);
+
+    let mut client = streaming_client(port).await;
+    client.send_log_line(&line("web-live-2", "streamed")).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), lines.recv()).await.unwrap().unwrap();
+    let report = common::stored_path(&DateTime::now(), "web-live-2||09:00:00.log");
+    std::fs::create_dir_all(report.parent().unwrap()).unwrap();
+    std::fs::write(&report, "RUNNING CONTAINERS: 0\n").unwrap();
+
+    let response = get(http_port, "/logs").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains(&*report.to_string_lossy()), "{}", response);
+    assert!(!response.contains("web-live-2.live"), "{}", response);
+}
+
+#[tokio::test]
+async fn watchers_get_lines_for_their_host() {
+    let state = CentralState::new();
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0), state.clone()).await;
+    let http_port = start_http_server(state);
+
+    let mut watcher = TcpStream::connect(("127.0.0.1", http_port)).await.unwrap();
+    watcher.write_all(b"GET /live/web-live-3 HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    // The watcher is subscribed by the time the response starts
+    let mut received = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !String::from_utf8_lossy(&received).contains("\r\n\r\n") {
+        let n = tokio::time::timeout(Duration::from_secs(5), watcher.read(&mut buf)).await.unwrap().unwrap();
+        assert!(n > 0);
+        received.extend_from_slice(&buf[..n]);
+    }
+    let headers = String::from_utf8_lossy(&received).to_string();
+    assert!(headers.starts_with("HTTP/1.1 200"), "{}", headers);
+    assert!(headers.contains("text/event-stream"), "{}", headers);
+
+    let mut client = streaming_client(port).await;
+    client.send_log_line(&line("web-live-4", "for another host")).await.unwrap();
+    client.send_log_line(&line("web-live-3", "for the watcher")).await.unwrap();
+
+    while !String::from_utf8_lossy(&received).contains("data:for the watcher") {
+        let n = tokio::time::timeout(Duration::from_secs(5), watcher.read(&mut buf)).await.unwrap().unwrap();
+        assert!(n > 0);
+        received.extend_from_slice(&buf[..n]);
+    }
+    let events = String::from_utf8_lossy(&received).to_string();
+    assert!(events.contains("event:line\ndata:for the watcher\n"), "{}", events);
+    // Sent first, so it would have arrived before
+    assert!(!events.contains("for another host"), "{}", events);
+}
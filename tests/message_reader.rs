@@ -0,0 +1,133 @@
+/**
+ * Reading frames out of a byte stream, and carrying on past corrupt ones
+ */
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+use lib_setup::codec::MessageCodec;
+use lib_setup::constants::METADATA_SIZE_V4;
+use lib_setup::error::FrameError;
+use lib_setup::message::Message;
+use lib_setup::message_reader::MessageReader;
+
+fn frame(text: &str) -> Vec<u8> {
+    Message::new(text).encode().unwrap()
+}
+
+fn texts(messages: &[Message]) -> Vec<&str> {
+    messages.iter().map(|message| message.text()).collect()
+}
+
+#[test]
+fn frame_split_across_reads() {
+    let mut reader = MessageReader::new();
+    let mut data = frame("7 status");
+    data.extend(frame("8 list"));
+
+    let (first, rest) = data.split_at(5);
+    assert!(reader.read(first).is_empty());
+    assert_eq!(texts(&reader.read(rest)), vec!["7 status", "8 list"]);
+    assert_eq!(reader.dropped_frames, 0);
+    assert!(reader.buffer.is_empty());
+}
+
+#[test]
+fn skips_garbage_before_a_frame() {
+    let mut reader = MessageReader::new();
+    let mut data = b"\x00\x07noise".to_vec();
+    data.extend(frame("7 status"));
+
+    assert_eq!(texts(&reader.read(&data)), vec!["7 status"]);
+    assert_eq!(reader.dropped_frames, 1);
+    assert_eq!(reader.dropped_bytes, 7);
+    assert_eq!(reader.take_errors(), vec![FrameError::UnsupportedVersion(0)]);
+    // Errors are only handed out once
+    assert!(reader.take_errors().is_empty());
+}
+
+#[test]
+fn resyncs_after_a_corrupt_header() {
+    let mut reader = MessageReader::new();
+    let mut corrupt = frame("7 status");
+    // Marker intact, version unknown
+    corrupt[2] = 99;
+    let mut data = corrupt.clone();
+    data.extend(frame("8 list"));
+
+    assert_eq!(texts(&reader.read(&data)), vec!["8 list"]);
+    assert_eq!(reader.dropped_frames, 1);
+    assert_eq!(reader.dropped_bytes, corrupt.len() as u64);
+    assert_eq!(reader.take_errors(), vec![FrameError::UnsupportedVersion(99)]);
+}
+
+#[test]
+fn resyncs_after_a_corrupt_length() {
+    let mut reader = MessageReader::new();
+    let mut corrupt = frame("7 status");
+    // Announces megabytes of content that will never arrive
+    corrupt[METADATA_SIZE_V4 - 3] ^= 0x10;
+    let mut data = corrupt.clone();
+    data.extend(frame("8 list"));
+
+    // Read without waiting for the length it announced
+    assert_eq!(texts(&reader.read(&data)), vec!["8 list"]);
+    assert_eq!(reader.dropped_frames, 1);
+    assert_eq!(reader.dropped_bytes, corrupt.len() as u64);
+    assert!(matches!(reader.take_errors()[..], [FrameError::HeaderChecksum(_)]));
+    assert!(reader.buffer.is_empty());
+}
+
+#[test]
+fn drops_a_frame_with_an_unknown_type() {
+    let mut reader = MessageReader::new();
+    // Version 4, as a later version's checksums would catch the change first
+    let mut unknown = Message::new("7 status").with_version(4).encode().unwrap();
+    unknown[3] = 200;
+    let mut data = unknown.clone();
+    data.extend(frame("8 list"));
+
+    // The header is sound, so only that frame is thrown away
+    assert_eq!(texts(&reader.read(&data)), vec!["8 list"]);
+    assert_eq!(reader.dropped_bytes, unknown.len() as u64);
+    assert_eq!(reader.take_errors(), vec![FrameError::UnknownFrameType(200)]);
+}
+
+#[test]
+fn skips_frames_over_the_maximum() {
+    let mut reader = MessageReader::with_max_frame_size(16);
+    let mut data = frame(&"x".repeat(17));
+    data.extend(frame("7 status"));
+
+    assert_eq!(texts(&reader.read(&data)), vec!["7 status"]);
+    assert_eq!(reader.dropped_frames, 1);
+    assert_eq!(reader.take_errors(), vec![FrameError::TooLarge { length: 17, max: 16 }]);
+}
+
+#[test]
+fn keeps_what_may_be_half_a_marker() {
+    let mut reader = MessageReader::new();
+    let data = frame("7 status");
+
+    // Garbage ending in the first marker byte, then the rest of the frame
+    let mut first = b"\x00\x00".to_vec();
+    first.push(data[0]);
+    assert!(reader.read(&first).is_empty());
+    assert_eq!(reader.dropped_bytes, 2);
+    assert_eq!(texts(&reader.read(&data[1..])), vec!["7 status"]);
+}
+
+#[test]
+fn codec_skips_corrupt_frames() {
+    let mut codec = MessageCodec::new();
+    let mut corrupt = frame("7 status");
+    corrupt[2] = 99;
+    let mut buffer = BytesMut::from(&corrupt[..]);
+    buffer.extend_from_slice(&frame("8 list"));
+
+    let message = codec.decode(&mut buffer).unwrap().unwrap();
+    assert_eq!(message.text(), "8 list");
+    assert!(codec.decode(&mut buffer).unwrap().is_none());
+    assert_eq!(codec.dropped_frames, 1);
+    assert_eq!(codec.dropped_bytes, corrupt.len() as u64);
+    assert_eq!(codec.take_errors(), vec![FrameError::UnsupportedVersion(99)]);
+}
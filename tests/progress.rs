@@ -0,0 +1,175 @@
+/**
+ * Following a transfer through its progress updates, and cancelling it with its token
+ */
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio_util::codec::Framed;
+use tokio_util::sync::CancellationToken;
+
+use lib_setup::central_state::CentralState;
+use lib_setup::client::Client;
+use lib_setup::codec::MessageCodec;
+use lib_setup::datetime::DateTime;
+use lib_setup::error::TransferCancelled;
+use lib_setup::file_info::FileInfo;
+use lib_setup::handshake::Hello;
+use lib_setup::message::{FrameType, Message, ReplyStatus};
+use lib_setup::progress::{TransferOptions, TransferProgress};
+use lib_setup::server::Server;
+
+mod common;
+
+const FILE_LEN: u64 = 64 * 1024 * 1024;
+
+// A storing server that agrees to capabilities alone, hands the test every frame it receives
+// and sends whatever the test gives it. Without "zstd" or "gzip" files are sent as they are.
+// Given held, it stops reading after a file header until that is notified.
+async fn start_fake_server(capabilities: &'static [&'static str], held: Option<Arc<Notify>>) -> (u16, mpsc::Receiver<Message>, mpsc::Sender<Message>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (received, frames_received) = mpsc::channel(64);
+    let (frames_to_send, mut to_send) = mpsc::channel::<Message>(4);
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut frames = Framed::new(socket, MessageCodec::new());
+        let Some(Ok(message)) = frames.next().await else {
+            return;
+        };
+        let local = Hello { capabilities: capabilities.iter().map(|c| c.to_string()).collect(), ..Hello::local() };
+        let session = local.negotiate(&Hello::from_message(&message).unwrap()).unwrap();
+        frames.send(Hello::from_session(&session).to_message()).await.unwrap();
+        loop {
+            tokio::select! {
+                Some(Ok(message)) = frames.next() => {
+                    let kind = message.kind;
+                    if received.send(message).await.is_err() {
+                        break;
+                    }
+                    if let (FrameType::FileHeader, Some(held)) = (kind, &held) {
+                        held.notified().await;
+                    }
+                }
+                Some(message) = to_send.recv() => frames.send(message.with_version(session.version)).await.unwrap(),
+                else => break,
+            }
+        }
+    });
+    (port, frames_received, frames_to_send)
+}
+
+// The next frame the fake server received, skipping chunks
+async fn next_other_than_chunk(received: &mut mpsc::Receiver<Message>) -> Message {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap();
+        if message.kind != FrameType::FileChunk {
+            return message;
+        }
+    }
+}
+
+fn is_cancel(message: &Message) -> bool {
+    message.kind == FrameType::FileAck && message.to_file_ack().unwrap() == ReplyStatus::Error("cancelled".to_string())
+}
+
+#[tokio::test]
+async fn progress_reaches_the_whole_file() {
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0), CentralState::new()).await;
+    let path = common::temp_dir("progress").join("web-1||12:00:00.log");
+    std::fs::write(&path, common::incompressible(1_000_000)).unwrap();
+
+    let (progress, mut updates) = watch::channel(TransferProgress::default());
+    let watching = tokio::spawn(async move {
+        let mut seen = Vec::new();
+        while updates.changed().await.is_ok() {
+            seen.push(updates.borrow_and_update().sent);
+        }
+        (seen, updates.borrow().clone())
+    });
+    let options = TransferOptions::new().with_progress(progress);
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    client.handshake().await.unwrap();
+    client.send_file_with(path.to_string_lossy().to_string(), DateTime::now(), &options).await.unwrap();
+    drop(options);
+
+    let (seen, last) = watching.await.unwrap();
+    assert!(seen.is_sorted(), "{:?}", seen);
+    assert_eq!(last.filename, "web-1||12:00:00.log");
+    assert!(last.total > 0);
+    assert_eq!(last.sent, last.total);
+    assert_eq!(last.fraction(), 1.0);
+    assert_eq!(last.eta, Some(Duration::ZERO));
+}
+
+#[tokio::test]
+async fn cancelled_transfer_stops_and_tells_the_server() {
+    let held = Arc::new(Notify::new());
+    let (port, mut received, _answers) = start_fake_server(&["replies", "file_ack", "cancel"], Some(held.clone())).await;
+    let path = common::temp_dir("progress_cancel").join("web-1||12:00:01.log");
+    // More than the connection buffers, so the client waits on the server part way through
+    std::fs::write(&path, vec![0u8; FILE_LEN as usize]).unwrap();
+
+    // Cancelled from another task once the client is held up, and the server let go
+    let (progress, mut updates) = watch::channel(TransferProgress::default());
+    let last = progress.subscribe();
+    let cancel = CancellationToken::new();
+    {
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            while updates.changed().await.is_ok() {
+                if updates.borrow_and_update().sent > 0 {
+                    cancel.cancel();
+                    held.notify_one();
+                    break;
+                }
+            }
+        });
+    }
+    let options = TransferOptions::new().with_progress(progress).with_cancel(cancel);
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    assert!(!client.handshake().await.unwrap().has("resume"));
+    let e = client.send_file_with(path.to_string_lossy().to_string(), DateTime::now(), &options).await.unwrap_err();
+
+    let cancelled = e.downcast::<TransferCancelled>().unwrap();
+    assert_eq!(cancelled.total, FILE_LEN);
+    assert!(cancelled.sent > 0 && cancelled.sent < cancelled.total, "{}", cancelled);
+    // The chunk being sent when the token was cancelled is the last
+    let last = last.borrow().clone();
+    assert_eq!(last.sent, cancelled.sent);
+    assert!(last.eta.is_some_and(|eta| eta > Duration::ZERO));
+
+    assert_eq!(next_other_than_chunk(&mut received).await.kind, FrameType::FileHeader);
+    assert!(is_cancel(&next_other_than_chunk(&mut received).await));
+}
+
+#[tokio::test]
+async fn cancelled_resumable_transfer_counts_what_the_server_kept() {
+    let (port, mut received, answers) = start_fake_server(&["replies", "file_ack", "resume", "cancel"], None).await;
+    let path = common::temp_dir("progress_resume").join("web-1||12:00:02.log");
+    std::fs::write(&path, common::incompressible(1_000_000)).unwrap();
+
+    let (progress, updates) = watch::channel(TransferProgress::default());
+    let cancel = CancellationToken::new();
+    let options = TransferOptions::new().with_progress(progress).with_cancel(cancel.clone());
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    assert!(client.handshake().await.unwrap().has("resume"));
+    let sending = tokio::spawn(async move {
+        client.send_file_with(path.to_string_lossy().to_string(), DateTime::now(), &options).await
+    });
+
+    // Cancelled while the client waits to hear where to resume from
+    let header = next_other_than_chunk(&mut received).await;
+    assert!(FileInfo::decode(&header.content).unwrap().upload_id.is_some());
+    cancel.cancel();
+    answers.send(Message::file_resume(300_000)).await.unwrap();
+
+    let e = sending.await.unwrap().unwrap_err();
+    assert_eq!(e.downcast::<TransferCancelled>().unwrap(), TransferCancelled { filename: "web-1||12:00:02.log".to_string(), sent: 300_000, total: 1_000_000 });
+    let last = updates.borrow().clone();
+    assert_eq!((last.sent, last.total, last.eta), (300_000, 1_000_000, None));
+    // Nothing was sent after the resume point
+    let message = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap();
+    assert!(is_cancel(&message), "{:?}", message.kind);
+}
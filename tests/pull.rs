@@ -0,0 +1,73 @@
+/**
+ * Reports fetched by the central server from a logging server's command port, see pull.rs
+ */
+use std::os::unix::fs::PermissionsExt;
+use std::sync::OnceLock;
+use tokio::net::TcpListener;
+
+use lib_setup::auth::CommandKey;
+use lib_setup::error::AuthError;
+use lib_setup::log_utils;
+use lib_setup::logger_state::LoggerState;
+use lib_setup::pull;
+use lib_setup::server::Server;
+use lib_setup::upload::UploadConfig;
+
+mod common;
+
+// Collection shells out to lxc, which is replaced by a command that finds no containers
+fn stub_lxc() {
+    static STUBBED: OnceLock<()> = OnceLock::new();
+    STUBBED.get_or_init(|| {
+        let bin = common::temp_dir("pull_bin");
+        let lxc = bin.join("lxc");
+        std::fs::write(&lxc, "#!/bin/sh\nexit 0\n").unwrap();
+        std::fs::set_permissions(&lxc, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default());
+        // Every test calls this before anything runs a command
+        unsafe { std::env::set_var("PATH", path) };
+    });
+}
+
+// Listens on every address, as the logger's own name may resolve to one other than 127.0.0.1
+async fn start_logger(server: Server) -> u16 {
+    stub_lxc();
+    common::log_dir();
+    let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { server.run_logging_server_on(listener, LoggerState::new()).await });
+    port
+}
+
+#[tokio::test]
+async fn fetched_report_is_stored() {
+    let port = start_logger(Server::new("0.0.0.0", 0)).await;
+    let host = log_utils::get_hostname();
+
+    let path = pull::fetch_report(&host, port, None, &UploadConfig::default(), None, None).await.unwrap();
+    let filename = path.file_name().unwrap().to_string_lossy().to_string();
+    assert!(filename.starts_with(&format!("{}||", host)), "{}", filename);
+    assert!(path.starts_with(common::log_dir()));
+    assert!(!std::fs::read_to_string(&path).unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn report_for_another_host_is_refused() {
+    let port = start_logger(Server::new("0.0.0.0", 0)).await;
+
+    // Reports are named after the logger's hostname, not the address it was reached at
+    let e = pull::fetch_report("127.0.0.1", port, None, &UploadConfig::default(), None, None).await.unwrap_err();
+    let claimed = format!("sent a report for {:?}", log_utils::get_hostname());
+    assert!(e.to_string().contains(&claimed), "{}", e);
+}
+
+#[tokio::test]
+async fn keyed_logger_only_sends_signed_fetches() {
+    let key = CommandKey::new("secret");
+    let port = start_logger(Server::new("0.0.0.0", 0).with_auth(key.clone())).await;
+    let host = log_utils::get_hostname();
+
+    let e = pull::fetch_report(&host, port, None, &UploadConfig::default(), None, None).await.unwrap_err();
+    assert!(e.to_string().contains(&AuthError::Unsigned.to_string()), "{}", e);
+    pull::fetch_report(&host, port, None, &UploadConfig::default(), None, Some(key)).await.unwrap();
+}
@@ -0,0 +1,112 @@
+/**
+ * Commands the central server sends to a logger over the connection the logger opened to the
+ * storing server
+ */
+use std::time::Duration;
+
+use lib_setup::auth::{CommandKey, CommandVerifier};
+use lib_setup::central_state::CentralState;
+use lib_setup::client::Client;
+use lib_setup::error::{AuthError, ReplyTimedOut};
+use lib_setup::logger_state::LoggerState;
+use lib_setup::message::{Command, FrameType, ReplyStatus};
+use lib_setup::remote::{self, ConnectedLoggers};
+use lib_setup::server::Server;
+
+mod common;
+
+// Loggers without a client certificate are known by their address
+const HOST: &str = "127.0.0.1";
+
+// A logger connected to the storing server on port, answering commands in the background as
+// server_logger does
+async fn connect_logger(port: u16, state: LoggerState, verifier: Option<CommandVerifier>) {
+    let mut client = Client::connect(HOST, port).await.unwrap().with_commands();
+    assert!(client.handshake().await.unwrap().has("commands"));
+    tokio::spawn(async move {
+        while let Ok(message) = client.recv().await {
+            if message.kind == FrameType::Command {
+                remote::answer(&mut client, &state, verifier.as_ref(), &message).await.unwrap();
+            }
+        }
+    });
+}
+
+// The session registers the logger once it has answered the hello
+async fn wait_for_hosts(loggers: &ConnectedLoggers, expected: &[&str]) {
+    for _ in 0..500 {
+        if loggers.hosts().await == expected {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("connected loggers are {:?}, expected {:?}", loggers.hosts().await, expected);
+}
+
+#[tokio::test]
+async fn central_server_commands_a_connected_logger() {
+    let central = CentralState::new();
+    let port = common::start_storing_server(Server::new(HOST, 0), central.clone()).await;
+    let logger = LoggerState::new();
+    connect_logger(port, logger.clone(), None).await;
+    wait_for_hosts(&central.loggers, &[HOST]).await;
+
+    assert_eq!(central.loggers.send(HOST, Command::Pause).await.unwrap().status, ReplyStatus::Ok);
+    assert!(!*logger.running.lock().await);
+    let status = central.loggers.send(HOST, Command::Status).await.unwrap().status;
+    assert!(matches!(status, ReplyStatus::Payload(status) if status.contains("\"running\":false")));
+    // Closing the connection the command came over is left to the logger
+    assert!(matches!(central.loggers.send(HOST, Command::Exit).await.unwrap().status, ReplyStatus::Error(_)));
+
+    assert!(central.loggers.send("web-1", Command::Status).await.is_err());
+}
+
+#[tokio::test]
+async fn commands_are_signed_for_a_logger_with_a_key() {
+    let key = CommandKey::new("secret");
+    let signing = CentralState::new().with_command_key(key.clone());
+    let port = common::start_storing_server(Server::new(HOST, 0), signing.clone()).await;
+    let logger = LoggerState::new();
+    connect_logger(port, logger.clone(), Some(CommandVerifier::new(key.clone()))).await;
+    wait_for_hosts(&signing.loggers, &[HOST]).await;
+    assert_eq!(signing.loggers.send(HOST, Command::Pause).await.unwrap().status, ReplyStatus::Ok);
+    assert!(!*logger.running.lock().await);
+
+    let unsigned = CentralState::new();
+    let port = common::start_storing_server(Server::new(HOST, 0), unsigned.clone()).await;
+    connect_logger(port, logger.clone(), Some(CommandVerifier::new(key))).await;
+    wait_for_hosts(&unsigned.loggers, &[HOST]).await;
+    let reply = unsigned.loggers.send(HOST, Command::Resume).await.unwrap();
+    assert_eq!(reply.status, ReplyStatus::Error(AuthError::Unsigned.to_string()));
+    assert!(!*logger.running.lock().await);
+}
+
+#[tokio::test]
+async fn only_loggers_that_offer_commands_are_sent_them() {
+    let central = CentralState::new();
+    let port = common::start_storing_server(Server::new(HOST, 0), central.clone()).await;
+
+    let mut client = Client::connect(HOST, port).await.unwrap();
+    assert!(!client.handshake().await.unwrap().has("commands"));
+    // Answered after the hello, so the session has seen it
+    client.heartbeat().await.unwrap();
+    assert!(central.loggers.hosts().await.is_empty());
+    assert!(central.loggers.send(HOST, Command::Status).await.is_err());
+}
+
+#[tokio::test]
+async fn unanswered_command_times_out_and_closed_sessions_are_forgotten() {
+    let mut central = CentralState::new();
+    central.loggers = central.loggers.with_reply_timeout(Duration::from_millis(200));
+    let port = common::start_storing_server(Server::new(HOST, 0), central.clone()).await;
+
+    // Offers commands but never reads them
+    let mut client = Client::connect(HOST, port).await.unwrap().with_commands();
+    client.handshake().await.unwrap();
+    wait_for_hosts(&central.loggers, &[HOST]).await;
+    let e = central.loggers.send(HOST, Command::Status).await.unwrap_err();
+    assert!(e.is::<ReplyTimedOut>());
+
+    drop(client);
+    wait_for_hosts(&central.loggers, &[]).await;
+}
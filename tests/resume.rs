@@ -0,0 +1,107 @@
+/**
+ * Uploads picked up where an earlier attempt left off, from the partial file the storing
+ * server keeps for each upload id
+ */
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+use lib_setup::central_state::CentralState;
+use lib_setup::client::Client;
+use lib_setup::datetime::DateTime;
+use lib_setup::file_info::FileInfo;
+use lib_setup::log_utils;
+use lib_setup::message::{FrameType, Message, ReplyStatus};
+use lib_setup::server::Server;
+
+mod common;
+
+// The partial file the storing server keeps for upload_id
+fn partial_path(upload_id: &str) -> PathBuf {
+    let dir = PathBuf::from(log_utils::partial_upload_dir());
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(format!("{}.part", upload_id))
+}
+
+// A session that sends frames by hand, to choose the upload id
+async fn connect(port: u16) -> Client {
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    assert!(client.handshake().await.unwrap().has("resume"));
+    client
+}
+
+fn resumable_header(filename: &str, body: &[u8], upload_id: &str, datetime: &DateTime) -> Message {
+    let file_info = FileInfo::new(body.len() as u64, filename.to_string(), datetime.clone())
+        .with_sha256(Sha256::digest(body).into())
+        .with_upload_id(upload_id);
+    Message::with_kind(FrameType::FileHeader, file_info.encode())
+}
+
+async fn next(client: &mut Client, kind: FrameType) -> Message {
+    let message = client.recv().await.unwrap();
+    assert_eq!(message.kind, kind, "{:?}", message.text());
+    message
+}
+
+#[tokio::test]
+async fn dropped_upload_resumes_from_what_was_kept() {
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0), CentralState::new()).await;
+    let dir = common::temp_dir("resume_dropped");
+    let content = common::incompressible(1_000_000);
+    let path = dir.join("web-1||10:00:00.log");
+    std::fs::write(&path, &content).unwrap();
+    let datetime = DateTime::now();
+
+    // Cut off part way through the body
+    let (proxy_port, forwarding) = common::proxy(port, 400_000).await;
+    let mut client = connect(proxy_port).await;
+    assert!(client.send_file(path.to_string_lossy().to_string(), datetime.clone()).await.is_err());
+    drop(client);
+    assert_eq!(forwarding.await.unwrap(), 400_000);
+    assert!(!common::stored_path(&datetime, "web-1||10:00:00.log").exists());
+
+    // The retry only sends what the server did not keep
+    let (proxy_port, forwarding) = common::proxy(port, usize::MAX).await;
+    let mut client = connect(proxy_port).await;
+    client.send_file(path.to_string_lossy().to_string(), datetime.clone()).await.unwrap();
+    drop(client);
+    let resent = forwarding.await.unwrap();
+    // Whole chunks are kept, 300000 bytes of the 400000 that got through
+    assert!(resent + 200_000 < content.len(), "{} bytes sent again", resent);
+
+    assert_eq!(std::fs::read(common::stored_path(&datetime, "web-1||10:00:00.log")).unwrap(), content);
+}
+
+#[tokio::test]
+async fn complete_partial_is_stored_without_sending_the_body() {
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0), CentralState::new()).await;
+    let content = b"RUNNING CONTAINERS: 3\n".repeat(100);
+    let upload_id = "0123456789abcdef0123456789abcdef";
+    std::fs::write(partial_path(upload_id), &content).unwrap();
+    let datetime = DateTime::now();
+
+    let mut client = connect(port).await;
+    client.send_message(resumable_header("web-1||10:00:01.log", &content, upload_id, &datetime)).await.unwrap();
+    let offset = next(&mut client, FrameType::FileResume).await.to_file_resume().unwrap();
+    assert_eq!(offset, content.len() as u64);
+    assert_eq!(next(&mut client, FrameType::FileAck).await.to_file_ack().unwrap(), ReplyStatus::Ok);
+
+    assert_eq!(std::fs::read(common::stored_path(&datetime, "web-1||10:00:01.log")).unwrap(), content);
+    assert!(!partial_path(upload_id).exists());
+}
+
+#[tokio::test]
+async fn partial_longer_than_the_file_is_started_again() {
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0), CentralState::new()).await;
+    let content = b"RUNNING CONTAINERS: 4\n".repeat(100);
+    let upload_id = "fedcba9876543210fedcba9876543210";
+    std::fs::write(partial_path(upload_id), b"x".repeat(content.len() + 1)).unwrap();
+    let datetime = DateTime::now();
+
+    let mut client = connect(port).await;
+    client.send_message(resumable_header("web-1||10:00:02.log", &content, upload_id, &datetime)).await.unwrap();
+    assert_eq!(next(&mut client, FrameType::FileResume).await.to_file_resume().unwrap(), 0);
+    client.send_message(Message::with_kind(FrameType::FileChunk, content.clone())).await.unwrap();
+    assert_eq!(next(&mut client, FrameType::FileAck).await.to_file_ack().unwrap(), ReplyStatus::Ok);
+
+    assert_eq!(std::fs::read(common::stored_path(&datetime, "web-1||10:00:02.log")).unwrap(), content);
+}
@@ -0,0 +1,142 @@
+/**
+ * Reports waiting on the logger for the central server, and the delays between attempts
+ */
+use std::path::Path;
+use std::time::Duration;
+
+use lib_setup::central_state::CentralState;
+use lib_setup::client::Client;
+use lib_setup::datetime::DateTime;
+use lib_setup::limits::ConnectionLimits;
+use lib_setup::server::Server;
+use lib_setup::spool::{Backoff, Spool, SpooledReport};
+
+mod common;
+use common::temp_dir;
+
+fn datetime(time: &str) -> DateTime {
+    DateTime {
+        year: "2025".to_string(),
+        month: "07".to_string(),
+        day: "25".to_string(),
+        time: time.to_string(),
+    }
+}
+
+// Writes a report of len bytes and spools it
+async fn spool_report(spool: &Spool, dir: &Path, filename: &str, len: usize) {
+    let path = dir.join(filename);
+    std::fs::write(&path, "x".repeat(len)).unwrap();
+    let time = filename.split("||").last().unwrap().trim_end_matches(".log");
+    spool.add(&path.to_string_lossy(), &datetime(time)).await.unwrap();
+}
+
+fn filenames(reports: &[SpooledReport]) -> Vec<String> {
+    reports.iter().map(|report| report.path.file_name().unwrap().to_string_lossy().to_string()).collect()
+}
+
+#[tokio::test]
+async fn reports_are_kept_in_order() {
+    let dir = temp_dir("order");
+    let spool = Spool::new(dir.join("spool"), u64::MAX, usize::MAX);
+    // Spooled order wins over the time in the name
+    for filename in ["web-1||09:00:00.log", "web-1||08:00:00.log", "web-1||10:00:00.log"] {
+        spool_report(&spool, &dir, filename, 10).await;
+    }
+
+    let reports = spool.reports().await.unwrap();
+    assert_eq!(filenames(&reports), vec!["web-1||09:00:00.log", "web-1||08:00:00.log", "web-1||10:00:00.log"]);
+    assert_eq!(reports[1].datetime, datetime("08:00:00"));
+    assert_eq!(reports[1].len, 10);
+
+    spool.remove(&reports[1]).await.unwrap();
+    // Removing it again is not an error
+    spool.remove(&reports[1]).await.unwrap();
+    assert_eq!(filenames(&spool.reports().await.unwrap()), vec!["web-1||09:00:00.log", "web-1||10:00:00.log"]);
+
+    // New reports go behind the remaining ones
+    spool_report(&spool, &dir, "web-1||07:00:00.log", 10).await;
+    assert_eq!(filenames(&spool.reports().await.unwrap()).last().unwrap(), "web-1||07:00:00.log");
+}
+
+#[tokio::test]
+async fn oldest_reports_are_dropped_over_the_count() {
+    let dir = temp_dir("count");
+    let spool = Spool::new(dir.join("spool"), u64::MAX, 2);
+    for filename in ["web-1||09:00:00.log", "web-1||09:00:01.log", "web-1||09:00:02.log"] {
+        spool_report(&spool, &dir, filename, 10).await;
+    }
+    assert_eq!(filenames(&spool.reports().await.unwrap()), vec!["web-1||09:00:01.log", "web-1||09:00:02.log"]);
+}
+
+#[tokio::test]
+async fn oldest_reports_are_dropped_over_the_size() {
+    let dir = temp_dir("size");
+    let spool = Spool::new(dir.join("spool"), 25, usize::MAX);
+    for filename in ["web-1||09:00:00.log", "web-1||09:00:01.log", "web-1||09:00:02.log"] {
+        spool_report(&spool, &dir, filename, 10).await;
+    }
+    assert_eq!(filenames(&spool.reports().await.unwrap()), vec!["web-1||09:00:01.log", "web-1||09:00:02.log"]);
+
+    // The newest report is kept however large
+    spool_report(&spool, &dir, "web-1||09:00:03.log", 100).await;
+    assert_eq!(filenames(&spool.reports().await.unwrap()), vec!["web-1||09:00:03.log"]);
+}
+
+async fn start_storing_server(limits: ConnectionLimits) -> u16 {
+    common::start_storing_server(Server::new("127.0.0.1", 0).with_limits(limits), CentralState::new()).await
+}
+
+#[tokio::test]
+async fn reports_are_removed_once_stored_or_refused_for_good() {
+    let port = start_storing_server(ConnectionLimits::default()).await;
+    let dir = temp_dir("deliver");
+    let spool = Spool::new(dir.join("spool"), u64::MAX, usize::MAX);
+    spool_report(&spool, &dir, "web-1||09:00:00.log", 10).await;
+    // Refused by its header however often it is sent
+    spool_report(&spool, &dir, "web 1||09:00:01.log", 10).await;
+
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    client.handshake().await.unwrap();
+    for report in spool.reports().await.unwrap() {
+        spool.deliver(&mut client, &report).await.unwrap();
+    }
+    assert!(spool.reports().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn reports_are_kept_when_not_stored() {
+    // Every transfer is refused as busy, which may pass
+    let port = start_storing_server(ConnectionLimits { max_transfers: 0, ..ConnectionLimits::default() }).await;
+    let dir = temp_dir("busy");
+    let spool = Spool::new(dir.join("spool"), u64::MAX, usize::MAX);
+    spool_report(&spool, &dir, "web-1||09:00:00.log", 10).await;
+
+    let mut client = Client::connect("127.0.0.1", port).await.unwrap();
+    client.handshake().await.unwrap();
+    let report = spool.reports().await.unwrap().remove(0);
+    assert!(spool.deliver(&mut client, &report).await.is_err());
+    assert_eq!(filenames(&spool.reports().await.unwrap()), vec!["web-1||09:00:00.log"]);
+}
+
+#[test]
+fn backoff_doubles_up_to_the_ceiling() {
+    let (min, max) = (Duration::from_secs(5), Duration::from_secs(30));
+    let mut backoff = Backoff::new(min, max);
+    for expected in [5, 10, 20, 30, 30] {
+        let delay = backoff.next_delay();
+        let expected = Duration::from_secs(expected);
+        // Cut by up to half so that loggers do not all return at once
+        assert!(delay <= expected && delay >= expected / 2, "{:?} for {:?}", delay, expected);
+    }
+
+    backoff.reset();
+    let delay = backoff.next_delay();
+    assert!(delay <= min && delay >= min / 2, "{:?}", delay);
+}
+
+#[test]
+fn backoff_delays_vary() {
+    let delays: Vec<Duration> = (0..20).map(|_| Backoff::new(Duration::from_secs(60), Duration::from_secs(60)).next_delay()).collect();
+    assert!(delays.iter().any(|delay| *delay != delays[0]));
+}
@@ -0,0 +1,95 @@
+/**
+ * What the storing server leaves behind of uploads that never finished: nothing where the log
+ * would be stored, and no temporary or long abandoned partial files once it restarts
+ */
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use bytes::Bytes;
+use futures::SinkExt;
+use tokio::net::TcpStream;
+use tokio_util::codec::FramedWrite;
+
+use lib_setup::central_state::CentralState;
+use lib_setup::client::Client;
+use lib_setup::codec::{FileFrame, FileInfoCodec};
+use lib_setup::datetime::DateTime;
+use lib_setup::file_info::FileInfo;
+use lib_setup::log_utils;
+use lib_setup::message::{FrameType, Message};
+use lib_setup::server::Server;
+
+mod common;
+
+fn partial_dir() -> PathBuf {
+    common::log_dir();
+    let dir = PathBuf::from(log_utils::partial_upload_dir());
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn dropped_session_upload_is_not_stored() {
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0), CentralState::new()).await;
+    let content = common::incompressible(10_000);
+    let datetime = DateTime::now();
+
+    // Without an upload id, so there is nothing to resume
+    let (proxy_port, forwarding) = common::proxy(port, usize::MAX).await;
+    let mut client = Client::connect("127.0.0.1", proxy_port).await.unwrap();
+    client.handshake().await.unwrap();
+    let file_info = FileInfo::new(content.len() as u64, "web-1||11:00:00.log".to_string(), datetime.clone());
+    client.send_message(Message::with_kind(FrameType::FileHeader, file_info.encode())).await.unwrap();
+    client.send_message(Message::with_kind(FrameType::FileChunk, content[..5_000].to_vec())).await.unwrap();
+    drop(client);
+    forwarding.await.unwrap();
+
+    assert!(!common::stored_path(&datetime, "web-1||11:00:00.log").exists());
+}
+
+#[tokio::test]
+async fn dropped_legacy_upload_is_not_stored() {
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0), CentralState::new()).await;
+    let content = common::incompressible(10_000);
+    let datetime = DateTime::now();
+
+    let (proxy_port, forwarding) = common::proxy(port, usize::MAX).await;
+    let stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+    let mut writer = FramedWrite::new(stream, FileInfoCodec::new());
+    let file_info = FileInfo::new(content.len() as u64, "web-1||11:00:01.log".to_string(), datetime.clone());
+    writer.send(FileFrame::Header(file_info)).await.unwrap();
+    writer.send(FileFrame::Chunk(Bytes::copy_from_slice(&content[..5_000]))).await.unwrap();
+    drop(writer);
+    forwarding.await.unwrap();
+
+    assert!(!common::stored_path(&datetime, "web-1||11:00:01.log").exists());
+}
+
+async fn wait_until_removed(path: &Path) {
+    for _ in 0..500 {
+        if !path.exists() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("{:?} was not removed", path);
+}
+
+#[tokio::test]
+async fn startup_removes_temporary_files_and_abandoned_partials() {
+    let dir = partial_dir();
+    let temporary = dir.join("1-0.tmp");
+    std::fs::write(&temporary, "half a report").unwrap();
+    // Untouched for longer than partial uploads are kept
+    let abandoned = dir.join("0123456789abcdef0123456789abcdef.part");
+    let file = std::fs::File::create(&abandoned).unwrap();
+    file.set_modified(SystemTime::now() - Duration::from_secs(8 * 24 * 60 * 60)).unwrap();
+    let recent = dir.join("fedcba9876543210fedcba9876543210.part");
+    std::fs::write(&recent, "half a report").unwrap();
+
+    // Port 0 as nothing connects to it
+    let server = Server::new("127.0.0.1", 0);
+    tokio::spawn(async move { server.run_storing_server(CentralState::new()).await });
+    wait_until_removed(&temporary).await;
+    wait_until_removed(&abandoned).await;
+    assert!(recent.exists());
+}
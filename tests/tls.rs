@@ -0,0 +1,154 @@
+/**
+ * Commands and file transfers over TLS, using certificates generated for each test. Loggers
+ * identified by a client certificate may only send reports for the host it names.
+ */
+use std::path::{Path, PathBuf};
+
+use lib_setup::central_state::CentralState;
+use lib_setup::client::Client;
+use lib_setup::datetime::DateTime;
+use lib_setup::error::UploadRejected;
+use lib_setup::logger_state::LoggerState;
+use lib_setup::message::{Command, ReplyStatus};
+use lib_setup::server::Server;
+use lib_setup::transport;
+
+mod common;
+use common::stored_path;
+
+// A certificate for localhost and its key, written to a fresh directory
+fn self_signed(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("tls_test_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert, certified.cert.pem()).unwrap();
+    std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    (dir, cert, key)
+}
+
+// A certificate authority, and a certificate it signed naming host, written next to each other
+fn client_certificate(dir: &Path, host: &str) -> (PathBuf, PathBuf, PathBuf) {
+    let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    ca_params.distinguished_name.push(rcgen::DnType::CommonName, "Logger CA");
+    let ca_key = rcgen::KeyPair::generate().unwrap();
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let mut params = rcgen::CertificateParams::new(vec![host.to_string()]).unwrap();
+    params.distinguished_name.push(rcgen::DnType::CommonName, host);
+    let key = rcgen::KeyPair::generate().unwrap();
+    let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+
+    let paths = (dir.join("client_ca.pem"), dir.join(format!("{}.pem", host)), dir.join(format!("{}.key", host)));
+    std::fs::write(&paths.0, ca.pem()).unwrap();
+    std::fs::write(&paths.1, cert.pem()).unwrap();
+    std::fs::write(&paths.2, key.serialize_pem()).unwrap();
+    paths
+}
+
+async fn start_logging_server(cert: &Path, key: &Path) -> u16 {
+    let server = Server::new("127.0.0.1", 0).with_tls(transport::acceptor(cert, key).unwrap());
+    common::start_logging_server(server, LoggerState::new()).await
+}
+
+#[tokio::test]
+async fn commands_over_tls() {
+    let (_, cert, key) = self_signed("commands");
+    let port = start_logging_server(&cert, &key).await;
+
+    let tls = transport::connector(&cert).unwrap();
+    let mut client = Client::connect_with("localhost", port, Some(&tls)).await.unwrap();
+    assert!(client.handshake().await.unwrap().version >= 4);
+    let reply = client.send_command(Command::Status).await.unwrap();
+    assert!(matches!(reply.status, ReplyStatus::Payload(status) if status.contains("interval_secs")));
+    assert_eq!(client.send_command(Command::Exit).await.unwrap().status, ReplyStatus::Ok);
+}
+
+#[tokio::test]
+async fn plain_client_is_refused() {
+    let (_, cert, key) = self_signed("plain");
+    let port = start_logging_server(&cert, &key).await;
+
+    let mut client = Client::connect("localhost", port).await.unwrap();
+    let answered = match client.handshake().await {
+        Ok(_) => client.send_command(Command::Status).await.is_ok(),
+        Err(_) => false,
+    };
+    assert!(!answered);
+}
+
+#[tokio::test]
+async fn untrusted_certificate_is_refused() {
+    let (_, cert, key) = self_signed("untrusted_server");
+    let (_, other, _) = self_signed("untrusted_other");
+    let port = start_logging_server(&cert, &key).await;
+
+    let tls = transport::connector(&other).unwrap();
+    assert!(Client::connect_with("localhost", port, Some(&tls)).await.is_err());
+}
+
+#[tokio::test]
+async fn file_transfer_over_tls() {
+    let (dir, cert, key) = self_signed("upload");
+    let server = Server::new("127.0.0.1", 0).with_tls(transport::acceptor(&cert, &key).unwrap());
+    let port = common::start_storing_server(server, CentralState::new()).await;
+
+    let report = dir.join("web-1||09:08:12.log");
+    std::fs::write(&report, "RUNNING CONTAINERS: (3, 4)\n".repeat(10000)).unwrap();
+    let tls = transport::connector(&cert).unwrap();
+    let mut client = Client::connect_with("localhost", port, Some(&tls)).await.unwrap();
+    client.handshake().await.unwrap();
+    let datetime = DateTime::now();
+    client.send_file(report.to_string_lossy().to_string(), datetime.clone()).await.unwrap();
+
+    let stored = stored_path(&datetime, "web-1||09:08:12.log");
+    assert_eq!(std::fs::read(stored).unwrap(), std::fs::read(&report).unwrap());
+}
+
+#[tokio::test]
+async fn uploads_are_attributed_to_the_client_certificate() {
+    let (dir, cert, key) = self_signed("mutual");
+    let (client_ca, client_cert, client_key) = client_certificate(&dir, "web-1");
+    let tls = transport::verifying_acceptor(&cert, &key, &client_ca).unwrap();
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0).with_tls(tls), CentralState::new()).await;
+
+    let tls = transport::identified_connector(&cert, &client_cert, &client_key).unwrap();
+    let mut client = Client::connect_with("localhost", port, Some(&tls)).await.unwrap();
+    client.handshake().await.unwrap();
+
+    let datetime = DateTime::now();
+    let own = dir.join("web-1||10:11:12.log");
+    std::fs::write(&own, "RUNNING CONTAINERS: (1, 2)\n").unwrap();
+    client.send_file(own.to_string_lossy().to_string(), datetime.clone()).await.unwrap();
+    let stored = stored_path(&datetime, "web-1||10:11:12.log");
+    assert_eq!(std::fs::read(stored).unwrap(), std::fs::read(&own).unwrap());
+
+    let forged = dir.join("db-1||10:11:12.log");
+    std::fs::write(&forged, "RUNNING CONTAINERS: (0, 9)\n").unwrap();
+    let e = client.send_file(forged.to_string_lossy().to_string(), datetime.clone()).await.unwrap_err();
+    let rejected = e.downcast::<UploadRejected>().unwrap();
+    assert!(rejected.reason.contains("web-1"), "{}", rejected.reason);
+    assert!(!stored_path(&datetime, "db-1||10:11:12.log").exists());
+
+    // The connection is still usable after a rejection
+    client.heartbeat().await.unwrap();
+}
+
+#[tokio::test]
+async fn client_without_certificate_is_refused() {
+    let (dir, cert, key) = self_signed("anonymous");
+    let (client_ca, _, _) = client_certificate(&dir, "web-1");
+    let tls = transport::verifying_acceptor(&cert, &key, &client_ca).unwrap();
+    let port = common::start_storing_server(Server::new("127.0.0.1", 0).with_tls(tls), CentralState::new()).await;
+
+    // TLS 1.3 clients finish their side of the handshake before the server checks them, so the
+    // refusal may only show once the connection is used
+    let tls = transport::connector(&cert).unwrap();
+    let refused = match Client::connect_with("localhost", port, Some(&tls)).await {
+        Ok(mut client) => client.handshake().await.is_err() || client.heartbeat().await.is_err(),
+        Err(_) => true,
+    };
+    assert!(refused);
+}
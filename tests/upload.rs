@@ -0,0 +1,51 @@
+/**
+ * Sizes announced by a file header, checked before anything is written
+ */
+use lib_setup::compression::Compression;
+use lib_setup::datetime::DateTime;
+use lib_setup::error::{UploadLimit, UploadLimitExceeded};
+use lib_setup::file_info::FileInfo;
+use lib_setup::upload::{IncomingFile, UploadConfig, MAX_FILE_SIZE};
+
+mod common;
+
+async fn refusal(file_info: FileInfo, config: &UploadConfig) -> UploadLimit {
+    common::log_dir();
+    let Err(e) = IncomingFile::create(file_info, config).await else {
+        panic!("upload was accepted");
+    };
+    e.downcast::<UploadLimitExceeded>().unwrap().limit
+}
+
+#[tokio::test]
+async fn absurd_original_length_is_refused() {
+    let config = UploadConfig { max_host_daily_size: Some(u64::MAX), ..UploadConfig::default() };
+    let file_info = FileInfo::new(100, "web-1||09:08:12.log".to_string(), DateTime::now())
+        .with_compression(Compression::Zstd, u64::MAX);
+    assert_eq!(refusal(file_info, &config).await, UploadLimit::FileSize { size: u64::MAX, max: MAX_FILE_SIZE });
+}
+
+#[tokio::test]
+async fn absurd_length_is_refused() {
+    let file_info = FileInfo::new(u64::MAX, "web-1||09:08:13.log".to_string(), DateTime::now());
+    let config = UploadConfig::default();
+    assert_eq!(refusal(file_info, &config).await, UploadLimit::FileSize { size: u64::MAX, max: MAX_FILE_SIZE });
+}
+
+#[tokio::test]
+async fn configured_limits_do_not_overflow() {
+    // Sizes up to the configured maximum are added without wrapping around
+    let config = UploadConfig {
+        max_file_size: Some(u64::MAX),
+        max_host_daily_size: Some(u64::MAX - 1),
+        ..UploadConfig::default()
+    };
+    let file_info = FileInfo::new(100, "web-1||09:08:14.log".to_string(), DateTime::now())
+        .with_compression(Compression::Zstd, u64::MAX);
+    assert!(matches!(refusal(file_info, &config).await, UploadLimit::HostQuota { .. }));
+
+    let config = UploadConfig { max_file_size: Some(u64::MAX), ..UploadConfig::default() };
+    let file_info = FileInfo::new(100, "web-1||09:08:15.log".to_string(), DateTime::now())
+        .with_compression(Compression::Zstd, u64::MAX);
+    assert!(matches!(refusal(file_info, &config).await, UploadLimit::DiskSpace { needed: u64::MAX, .. }));
+}